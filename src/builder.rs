@@ -0,0 +1,204 @@
+//! Builder-style configuration of `DwtSystick`
+
+use cortex_m::peripheral::syst::SystClkSource;
+#[cfg(not(armv6m))]
+use cortex_m::peripheral::{scb::SystemHandler, DCB, DWT, SCB, SYST};
+
+#[cfg(not(armv6m))]
+use crate::enable_trace;
+use crate::{
+    check_divisor, check_frequency,
+    hal::{CompareTimer, CycleCounter, DownCounter},
+    max_interval, DwtSystick, Epoch, Error, OverflowTracking, Rejected, SystickClock,
+    SYST_MAX_RELOAD, SYST_MIN_RELOAD,
+};
+
+/// Configuration of a [`DwtSystick`], validated before starting the counters.
+///
+/// ```no_run
+/// use dwt_systick_monotonic::{Builder, DwtSystick64, Epoch, SystickClock};
+///
+/// let mut cp = cortex_m::Peripherals::take().unwrap();
+/// let mono: DwtSystick64<72_000_000> = Builder::<72_000_000>::new(72_000_000)
+///     .systick_clock(SystickClock::External { divisor: 8 })
+///     .systick_priority(0xf0)
+///     .zero_counter(false)
+///     .epoch(Epoch::Reset)
+///     .build(&mut cp.DCB, &mut cp.SCB, cp.DWT, cp.SYST)
+///     .unwrap();
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct Builder<const TIMER_HZ: u32> {
+    sysclk: u32,
+    systick_clock: SystickClock,
+    priority: Option<u8>,
+    zero_counter: bool,
+    epoch: Epoch,
+    min_reload: u32,
+    tracking: OverflowTracking,
+}
+
+impl<const TIMER_HZ: u32> Builder<TIMER_HZ> {
+    /// The default configuration for a core clock of `sysclk`.
+    ///
+    /// Note that the `sysclk` parameter should come from e.g. the HAL's clock generation function
+    /// so the speed calculated at runtime and the declared speed (generic parameter
+    /// `TIMER_HZ`) can be compared.
+    pub fn new(sysclk: u32) -> Self {
+        Self {
+            sysclk,
+            systick_clock: SystickClock::Core,
+            priority: None,
+            zero_counter: true,
+            epoch: Epoch::Boot,
+            min_reload: SYST_MIN_RELOAD,
+            tracking: OverflowTracking::Interrupt,
+        }
+    }
+
+    /// Select the SysTick clock, see [`DwtSystick::with_systick_clock`].
+    pub fn systick_clock(mut self, clock: SystickClock) -> Self {
+        self.systick_clock = clock;
+        self
+    }
+
+    /// Set the SysTick exception priority, a raw value as taken by
+    /// `SCB::set_priority()`.
+    ///
+    /// Only applied by [`Builder::build`]. By default the priority is left
+    /// unchanged, e.g. for RTIC to set it.
+    pub fn systick_priority(mut self, priority: u8) -> Self {
+        self.priority = Some(priority);
+        self
+    }
+
+    /// Whether to clear the cycle counter when starting (the default).
+    ///
+    /// Otherwise the cycle counter keeps its count, e.g. from profiling
+    /// since boot, and the instants continue from it.
+    pub fn zero_counter(mut self, zero: bool) -> Self {
+        self.zero_counter = zero;
+        self
+    }
+
+    /// Select the origin of the instants, see [`DwtSystick::with_epoch`].
+    pub fn epoch(mut self, epoch: Epoch) -> Self {
+        self.epoch = epoch;
+        self
+    }
+
+    /// Set the minimum SysTick reload value, i.e. the shortest compare
+    /// interval in SysTick clocks.
+    ///
+    /// Compare events closer than this fire late. The default is the
+    /// hardware minimum of 1.
+    pub fn min_reload(mut self, reload: u32) -> Self {
+        self.min_reload = reload;
+        self
+    }
+
+    /// Select how the cycle counter overflows are tracked with `u64`
+    /// instants.
+    pub fn overflow_tracking(mut self, tracking: OverflowTracking) -> Self {
+        self.tracking = tracking;
+        self
+    }
+
+    /// Check the configuration without touching any peripheral.
+    pub fn validate(&self) -> Result<(), Error> {
+        check_frequency(TIMER_HZ, self.sysclk)?;
+        self.systick_clock.source()?;
+        if !(SYST_MIN_RELOAD..=SYST_MAX_RELOAD).contains(&self.min_reload) {
+            return Err(Error::InvalidReload(self.min_reload));
+        }
+        Ok(())
+    }
+
+    /// Check the SysTick clock and minimum reload value against the width of
+    /// the cycle counter and the timer, returning the SysTick clock.
+    fn check_parts<C: CycleCounter, T: CompareTimer>(
+        &self,
+        timer: &T,
+    ) -> Result<(SystClkSource, u32), Error> {
+        self.validate()?;
+        let (source, divisor) = self.systick_clock.source()?;
+        check_divisor(divisor, timer.max_divisor(), C::BITS)?;
+        // The minimum interval must fit the longest, see `DwtSystick::range()`.
+        if self.min_reload as u64 > max_interval(C::BITS) / divisor as u64 - 1 {
+            return Err(Error::InvalidReload(self.min_reload));
+        }
+        Ok((source, divisor))
+    }
+
+    /// Validate the configuration, enable the DWT and provide a new
+    /// `Monotonic` based on DWT and SysTick.
+    ///
+    /// Nothing is started if the configuration is invalid, also for the
+    /// width of the cycle counter, the peripherals are returned with the
+    /// error.
+    #[cfg(not(armv6m))]
+    pub fn build<W>(
+        self,
+        dcb: &mut DCB,
+        scb: &mut SCB,
+        dwt: DWT,
+        systick: SYST,
+    ) -> Result<DwtSystick<TIMER_HZ, DWT, SYST, W>, Rejected<DWT, SYST>> {
+        let clock = match self
+            .check_parts::<DWT, _>(&systick)
+            .and_then(|clock| enable_trace(dcb).map(|()| clock))
+        {
+            Ok(clock) => clock,
+            Err(error) => {
+                return Err(Rejected {
+                    error,
+                    counter: dwt,
+                    timer: systick,
+                })
+            }
+        };
+        if let Some(priority) = self.priority {
+            // NOTE(unsafe) SysTick is owned by the monotonic.
+            unsafe { scb.set_priority(SystemHandler::SysTick, priority) };
+        }
+        self.start(dwt, systick, clock)
+    }
+
+    /// Validate the configuration and provide a new `Monotonic` from a cycle
+    /// counter and a down-counter, see [`DwtSystick::from_parts`].
+    ///
+    /// Nothing is started if the configuration is invalid, also for the
+    /// width of the cycle counter, the peripherals are returned with the
+    /// error. The SysTick priority is not applied.
+    pub fn build_from_parts<C: CycleCounter, T: DownCounter, W>(
+        self,
+        counter: C,
+        timer: T,
+    ) -> Result<DwtSystick<TIMER_HZ, C, T, W>, Rejected<C, T>> {
+        let clock = match self.check_parts::<C, _>(&timer) {
+            Ok(clock) => clock,
+            Err(error) => {
+                return Err(Rejected {
+                    error,
+                    counter,
+                    timer,
+                })
+            }
+        };
+        self.start(counter, timer, clock)
+    }
+
+    /// Start the counters with the checked configuration.
+    fn start<C: CycleCounter, T: DownCounter, W>(
+        self,
+        counter: C,
+        timer: T,
+        (source, divisor): (SystClkSource, u32),
+    ) -> Result<DwtSystick<TIMER_HZ, C, T, W>, Rejected<C, T>> {
+        let mut mono = DwtSystick::try_start(counter, timer, self.zero_counter)?;
+        mono.apply_systick_clock(source, divisor);
+        mono.min_ticks = self.min_reload as u64;
+        mono.tracking = self.tracking;
+        Ok(mono.with_epoch(self.epoch))
+    }
+}
@@ -0,0 +1,65 @@
+//! Time from any context without access to the monotonic
+
+use core::marker::PhantomData;
+
+use cortex_m::peripheral::DWT;
+
+use crate::{Origin, Overflow, Ticks, CYCCNT_BITS};
+
+/// Handle reading the current time of a running [`DwtSystick`](crate::DwtSystick).
+///
+/// Obtained through [`DwtSystick::clock()`](crate::DwtSystick::clock). It is
+/// `Copy` and can be used from any interrupt priority or thread without
+/// locking, e.g. for logging timestamps outside the monotonic lock. It reads
+/// the cycle counter and shares the overflow state and the origin of the
+/// instants of the monotonic.
+///
+/// The instants are the same as the monotonic's `now()`, including the
+/// [`Epoch`](crate::Epoch): with [`Epoch::Reset`](crate::Epoch::Reset) they
+/// follow `reset()`. With `u64` instants a reader must not be preempted for
+/// longer than a quarter of the cycle counter period between reading the
+/// overflow state and the cycle counter.
+///
+/// The handles go stale while the monotonic is released by
+/// [`DwtSystick::release()`](crate::DwtSystick::release): nothing tracks the
+/// overflows until it is resumed, so `u64` instants read more than half a
+/// cycle counter period after releasing jump back by a period. Only read
+/// them again after resuming.
+#[derive(Clone, Copy, Debug)]
+pub struct Clock<const TIMER_HZ: u32, W = Ticks> {
+    overflow: &'static Overflow,
+    origin: &'static Origin,
+    _width: PhantomData<W>,
+}
+
+impl<const TIMER_HZ: u32, W> Clock<TIMER_HZ, W> {
+    /// # Safety
+    ///
+    /// The DWT cycle counter must be enabled, running at `TIMER_HZ` and
+    /// owned by a [`DwtSystick`](crate::DwtSystick) keeping `overflow` and
+    /// `origin` up to date.
+    pub(crate) unsafe fn new(overflow: &'static Overflow, origin: &'static Origin) -> Self {
+        Self {
+            overflow,
+            origin,
+            _width: PhantomData,
+        }
+    }
+}
+
+impl<const TIMER_HZ: u32> Clock<TIMER_HZ, u32> {
+    /// The current time.
+    pub fn now(&self) -> fugit::TimerInstantU32<TIMER_HZ> {
+        let origin = self.origin.read() as u32;
+        fugit::TimerInstantU32::from_ticks(DWT::cycle_count().wrapping_sub(origin))
+    }
+}
+
+impl<const TIMER_HZ: u32> Clock<TIMER_HZ, u64> {
+    /// The current time.
+    pub fn now(&self) -> fugit::TimerInstantU64<TIMER_HZ> {
+        let origin = self.origin.read();
+        let cycles = self.overflow.read(DWT::cycle_count, CYCCNT_BITS);
+        fugit::TimerInstantU64::from_ticks(cycles.wrapping_sub(origin))
+    }
+}
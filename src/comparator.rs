@@ -0,0 +1,169 @@
+//! `Monotonic` with compare events from a DWT comparator, leaving SysTick free
+
+use cortex_m::peripheral::{DCB, DWT};
+
+use crate::{check_frequency, enable_trace, hal::CompareTimer, DwtSystick, Error, Rejected, Ticks};
+
+/// `DEMCR.MON_EN`: enable the DebugMonitor exception.
+const DCB_DEMCR_MON_EN: u32 = 1 << 16;
+/// `DEMCR.MON_PEND`: pend the DebugMonitor exception.
+const DCB_DEMCR_MON_PEND: u32 = 1 << 17;
+
+/// `DWT_FUNCTION.CYCMATCH`: comparator 0 matches the cycle counter.
+const DWT_FUNCTION_CYCMATCH: u32 = 1 << 7;
+/// `DWT_FUNCTION.FUNCTION`: generate a debug event on match.
+const DWT_FUNCTION_DEBUG_EVENT: u32 = 0b0100;
+/// `DWT_FUNCTION.MATCHED`: the comparator matched since the last read.
+const DWT_FUNCTION_MATCHED: u32 = 1 << 24;
+
+/// DWT cycle counter and comparator implementing `rtic_monotonic::Monotonic`.
+///
+/// This is [`DwtSystick`] with the compare events generated by DWT
+/// comparator 0 matching the cycle counter. The match raises the
+/// DebugMonitor exception which must be bound to the monotonic (e.g.
+/// `#[monotonic(binds = DebugMonitor)]`). SysTick is left free for other
+/// uses, e.g. an RTOS tick.
+///
+/// Debug monitor events are only raised while halting debug is disabled: with
+/// a debugger attached and halting debug enabled, the match halts the core
+/// instead. Comparator 0 is reserved for the monotonic.
+///
+/// The width `W` of the instants is `u32` or `u64` (default [`Ticks`]). For
+/// `u64` the comparator is re-armed at least every quarter of the cycle
+/// counter period to track overflows.
+pub type DwtComparator<const TIMER_HZ: u32, W = Ticks> = DwtSystick<TIMER_HZ, DWT, Comparator, W>;
+
+/// DWT comparator 0 as the [`CompareTimer`] of a [`DwtComparator`].
+///
+/// It compares against the cycle counter, so the timer divisor must be 1
+/// ([`DwtSystick::with_timer_divisor`] rejects others).
+#[derive(Debug)]
+pub struct Comparator {
+    _private: (),
+}
+
+impl Comparator {
+    fn comparator() -> &'static cortex_m::peripheral::dwt::Comparator {
+        // NOTE(unsafe) Comparator 0 is owned by the monotonic.
+        unsafe { &(*DWT::PTR).c[0] }
+    }
+}
+
+impl CompareTimer for Comparator {
+    #[inline(always)]
+    fn max_ticks(&self) -> u64 {
+        u32::MAX as u64
+    }
+
+    #[inline(always)]
+    fn max_divisor(&self) -> u32 {
+        // The comparator matches the cycle counter itself.
+        1
+    }
+
+    #[inline(always)]
+    fn start(&mut self) {}
+
+    fn arm_relative(&mut self, ticks: u64) {
+        let now = DWT::cycle_count() as u64;
+        self.arm_absolute(now + ticks, now);
+    }
+
+    fn arm_absolute(&mut self, at: u64, now: u64) {
+        let comparator = Self::comparator();
+        unsafe {
+            comparator.comp.write(at as u32);
+            comparator
+                .function
+                .write(DWT_FUNCTION_CYCMATCH | DWT_FUNCTION_DEBUG_EVENT);
+        }
+        // The comparator only matches on equality: pend the exception if
+        // the target has passed while arming.
+        let elapsed = DWT::cycle_count().wrapping_sub(now as u32);
+        if elapsed as u64 >= at - now {
+            // NOTE(unsafe) Atomic set of a bit only used by the monotonic.
+            unsafe { (*DCB::PTR).demcr.modify(|w| w | DCB_DEMCR_MON_PEND) };
+        }
+    }
+
+    fn clear(&mut self) -> bool {
+        // Reading clears `MATCHED`.
+        let matched = Self::comparator().function.read() & DWT_FUNCTION_MATCHED != 0;
+        self.disarm();
+        matched
+    }
+
+    fn disarm(&mut self) {
+        let comparator = Self::comparator();
+        unsafe { comparator.function.write(DWT_FUNCTION_CYCMATCH) };
+        comparator.function.read();
+    }
+
+    fn stop(&mut self) {
+        self.disarm();
+        // NOTE(unsafe) Atomic clear of a bit only used by the monotonic.
+        unsafe { (*DCB::PTR).demcr.modify(|w| w & !DCB_DEMCR_MON_PEND) };
+    }
+}
+
+impl<const TIMER_HZ: u32, W> DwtSystick<TIMER_HZ, DWT, Comparator, W> {
+    /// Enable the DWT cycle counter, comparator 0 and the DebugMonitor exception
+    /// and provide a new `Monotonic`.
+    ///
+    /// Note that the `sysclk` parameter should come from e.g. the HAL's clock generation function
+    /// so the speed calculated at runtime and the declared speed (generic parameter
+    /// `TIMER_HZ`) can be compared.
+    ///
+    /// # Panics
+    ///
+    /// On any configuration [`Error`], see [`DwtComparator::try_new`].
+    #[inline(always)]
+    pub fn new(dcb: &mut DCB, dwt: DWT, sysclk: u32) -> Self {
+        Self::try_new(dcb, dwt, sysclk).unwrap()
+    }
+
+    /// Enable the DWT cycle counter, comparator 0 and the DebugMonitor exception
+    /// and provide a new `Monotonic`.
+    ///
+    /// Like [`DwtComparator::new`] but returns an [`Error`] together with the
+    /// DWT if the configuration is invalid or the DWT can not be enabled, e.g.
+    /// to fall back to a [`DwtSystick`].
+    pub fn try_new(dcb: &mut DCB, dwt: DWT, sysclk: u32) -> Result<Self, Rejected<DWT, ()>> {
+        if let Err(error) = check_frequency(TIMER_HZ, sysclk)
+            .and_then(|()| enable_trace(dcb))
+            .and_then(|()| Self::enable_comparator(dcb, &dwt))
+        {
+            return Err(Rejected {
+                error,
+                counter: dwt,
+                timer: (),
+            });
+        }
+
+        Self::try_from_parts(dwt, Comparator { _private: () }, sysclk).map_err(|rejected| {
+            Rejected {
+                error: rejected.error,
+                counter: rejected.counter,
+                timer: (),
+            }
+        })
+    }
+
+    /// Set up comparator 0 to match the cycle counter and enable the
+    /// DebugMonitor exception.
+    fn enable_comparator(dcb: &mut DCB, dwt: &DWT) -> Result<(), Error> {
+        if DWT::num_comp() == 0 {
+            return Err(Error::NoComparator);
+        }
+        let comparator = &dwt.c[0];
+        unsafe {
+            comparator.mask.write(0);
+            comparator.function.write(DWT_FUNCTION_CYCMATCH);
+        }
+        if comparator.function.read() & DWT_FUNCTION_CYCMATCH == 0 {
+            return Err(Error::NoComparator);
+        }
+        unsafe { dcb.demcr.modify(|w| w | DCB_DEMCR_MON_EN) };
+        Ok(())
+    }
+}
@@ -0,0 +1,50 @@
+//! Deadline-miss reporting
+
+/// Compare events requested for instants already in the past, see
+/// [`DwtSystick::deadline_misses()`](crate::DwtSystick::deadline_misses).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DeadlineMisses {
+    /// Number of missed deadlines.
+    pub count: u32,
+    /// Lateness of the most recent miss in cycles.
+    pub last: u64,
+    /// Largest lateness in cycles.
+    pub max: u64,
+}
+
+/// Records missed deadlines and calls the hook.
+pub(crate) struct DeadlineMonitor {
+    misses: DeadlineMisses,
+    hook: Option<fn(u64)>,
+}
+
+impl DeadlineMonitor {
+    pub(crate) const fn new() -> Self {
+        Self {
+            misses: DeadlineMisses {
+                count: 0,
+                last: 0,
+                max: 0,
+            },
+            hook: None,
+        }
+    }
+
+    pub(crate) fn misses(&self) -> DeadlineMisses {
+        self.misses
+    }
+
+    pub(crate) fn set_hook(&mut self, hook: fn(u64)) {
+        self.hook = Some(hook);
+    }
+
+    /// A compare event was requested `lateness` cycles in the past.
+    pub(crate) fn missed(&mut self, lateness: u64) {
+        self.misses.count = self.misses.count.wrapping_add(1);
+        self.misses.last = lateness;
+        self.misses.max = self.misses.max.max(lateness);
+        if let Some(hook) = self.hook {
+            hook(lateness);
+        }
+    }
+}
@@ -0,0 +1,54 @@
+//! Busy-wait `embedded_hal::delay::DelayNs` on the DWT cycle counter
+
+use cortex_m::peripheral::DWT;
+use embedded_hal::delay::DelayNs;
+
+use crate::cycles_at_least;
+
+/// Busy-waiting delay provider counting DWT cycles.
+///
+/// Obtained from a running [`DwtSystick`](crate::DwtSystick) through
+/// [`DwtSystick::delay()`](crate::DwtSystick::delay) and only reads the cycle
+/// counter. It is `Copy` and can be used alongside the monotonic.
+///
+/// The delay is accumulated from successive cycle counter differences. It
+/// thus supports cycle counter overflows and durations longer than the
+/// cycle counter period as long as the delay loop is not preempted for a
+/// full cycle counter period.
+#[derive(Clone, Copy, Debug)]
+pub struct Delay<const TIMER_HZ: u32> {
+    _private: (),
+}
+
+impl<const TIMER_HZ: u32> Delay<TIMER_HZ> {
+    /// # Safety
+    ///
+    /// The DWT cycle counter must be enabled and running at `TIMER_HZ`.
+    pub(crate) unsafe fn new() -> Self {
+        Self { _private: () }
+    }
+
+    /// Wait for at least `cycles` cycles.
+    pub fn delay_cycles(&mut self, mut cycles: u64) {
+        let mut last = DWT::cycle_count();
+        while cycles > 0 {
+            let now = DWT::cycle_count();
+            cycles = cycles.saturating_sub(now.wrapping_sub(last) as u64);
+            last = now;
+        }
+    }
+}
+
+impl<const TIMER_HZ: u32> DelayNs for Delay<TIMER_HZ> {
+    fn delay_ns(&mut self, ns: u32) {
+        self.delay_cycles(cycles_at_least(ns, 1_000_000_000, TIMER_HZ));
+    }
+
+    fn delay_us(&mut self, us: u32) {
+        self.delay_cycles(cycles_at_least(us, 1_000_000, TIMER_HZ));
+    }
+
+    fn delay_ms(&mut self, ms: u32) {
+        self.delay_cycles(cycles_at_least(ms, 1_000, TIMER_HZ));
+    }
+}
@@ -0,0 +1,188 @@
+//! `embassy-time` driver based on DWT and SysTick
+//!
+//! Enabling the `embassy` feature registers a [`DwtSystick64`] as the global
+//! [`embassy_time_driver::Driver`] and defines the SysTick exception handler
+//! for it. The cycle counter is always extended to `u64` and the embassy tick
+//! rate must be the core clock, i.e. the matching `tick-hz-*` feature of
+//! `embassy-time` must be selected.
+//!
+//! The driver provides [`ALARM_COUNT`] alarms sharing the single SysTick
+//! compare: it is always set to the earliest pending alarm.
+//!
+//! ```no_run
+//! let mut cp = cortex_m::Peripherals::take().unwrap();
+//! dwt_systick_monotonic::embassy::init(&mut cp.DCB, cp.DWT, cp.SYST, 72_000_000);
+//! ```
+
+use core::cell::{Cell, RefCell};
+
+use cortex_m::peripheral::{DCB, DWT, SYST};
+use critical_section::{CriticalSection, Mutex};
+use embassy_time_driver::{AlarmHandle, Driver, TICK_HZ};
+use rtic_monotonic::Monotonic;
+
+use crate::DwtSystick64;
+
+/// Number of alarms the driver provides.
+pub const ALARM_COUNT: usize = 4;
+
+type Mono = DwtSystick64<{ TICK_HZ as u32 }>;
+
+/// Alarm callback and its context.
+type AlarmCallback = (fn(*mut ()), *mut ());
+
+struct AlarmState {
+    timestamp: Cell<u64>,
+    callback: Cell<Option<AlarmCallback>>,
+}
+
+// NOTE(unsafe) The callback context is only accessed within critical sections.
+unsafe impl Send for AlarmState {}
+
+impl AlarmState {
+    const fn new() -> Self {
+        Self {
+            timestamp: Cell::new(u64::MAX),
+            callback: Cell::new(None),
+        }
+    }
+}
+
+struct DwtSystickDriver {
+    mono: Mutex<RefCell<Option<Mono>>>,
+    alarm_count: Mutex<Cell<u8>>,
+    alarms: Mutex<[AlarmState; ALARM_COUNT]>,
+}
+
+embassy_time_driver::time_driver_impl!(static DRIVER: DwtSystickDriver = DwtSystickDriver {
+    mono: Mutex::new(RefCell::new(None)),
+    alarm_count: Mutex::new(Cell::new(0)),
+    alarms: Mutex::new([
+        AlarmState::new(),
+        AlarmState::new(),
+        AlarmState::new(),
+        AlarmState::new(),
+    ]),
+});
+
+/// Enable the DWT and SysTick and start the embassy time driver.
+///
+/// Note that the `sysclk` parameter should come from e.g. the HAL's clock generation function
+/// so the speed calculated at runtime and the declared speed (embassy `TICK_HZ`) can be
+/// compared.
+pub fn init(dcb: &mut DCB, dwt: DWT, systick: SYST, sysclk: u32) {
+    let mono = Mono::new(dcb, dwt, systick, sysclk);
+    critical_section::with(|cs| DRIVER.mono.borrow(cs).replace(Some(mono)));
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+unsafe extern "C" fn SysTick() {
+    DRIVER.on_interrupt();
+}
+
+impl DwtSystickDriver {
+    fn on_interrupt(&self) {
+        critical_section::with(|cs| {
+            let mut expired = [None; ALARM_COUNT];
+            {
+                let mut mono = self.mono.borrow_ref_mut(cs);
+                let Some(mono) = mono.as_mut() else {
+                    return;
+                };
+                mono.clear_compare_flag();
+                mono.on_interrupt();
+
+                let now = mono.now().ticks();
+                for (alarm, expired) in self.alarms.borrow(cs).iter().zip(&mut expired) {
+                    if alarm.timestamp.get() <= now {
+                        alarm.timestamp.set(u64::MAX);
+                        *expired = alarm.callback.get();
+                    }
+                }
+            }
+
+            // The callbacks may set new alarms and thus need the monotonic.
+            for (callback, ctx) in expired.into_iter().flatten() {
+                callback(ctx);
+            }
+
+            if let Some(mono) = self.mono.borrow_ref_mut(cs).as_mut() {
+                self.set_compare(cs, mono);
+            }
+        });
+    }
+
+    /// Set the SysTick compare to the earliest pending alarm.
+    fn set_compare(&self, cs: CriticalSection, mono: &mut Mono) {
+        let next = self
+            .alarms
+            .borrow(cs)
+            .iter()
+            .map(|alarm| alarm.timestamp.get())
+            .min()
+            .unwrap_or(u64::MAX);
+        if next != u64::MAX {
+            // Alarms further away than the SysTick range are reached by
+            // re-arming on each interrupt.
+            mono.set_compare(fugit::TimerInstantU64::from_ticks(next));
+        }
+    }
+}
+
+impl Driver for DwtSystickDriver {
+    fn now(&self) -> u64 {
+        critical_section::with(|cs| {
+            self.mono
+                .borrow_ref_mut(cs)
+                .as_mut()
+                .map_or(0, |mono| mono.now().ticks())
+        })
+    }
+
+    unsafe fn allocate_alarm(&self) -> Option<AlarmHandle> {
+        critical_section::with(|cs| {
+            let count = self.alarm_count.borrow(cs);
+            let id = count.get();
+            if (id as usize) < ALARM_COUNT {
+                count.set(id + 1);
+                Some(AlarmHandle::new(id))
+            } else {
+                None
+            }
+        })
+    }
+
+    fn set_alarm_callback(&self, alarm: AlarmHandle, callback: fn(*mut ()), ctx: *mut ()) {
+        critical_section::with(|cs| {
+            self.alarms.borrow(cs)[alarm.id() as usize]
+                .callback
+                .set(Some((callback, ctx)));
+        })
+    }
+
+    fn set_alarm(&self, alarm: AlarmHandle, timestamp: u64) -> bool {
+        critical_section::with(|cs| {
+            let mut mono = self.mono.borrow_ref_mut(cs);
+            let Some(mono) = mono.as_mut() else {
+                return false;
+            };
+            let state = &self.alarms.borrow(cs)[alarm.id() as usize];
+            state.timestamp.set(timestamp);
+
+            if timestamp <= mono.now().ticks() {
+                state.timestamp.set(u64::MAX);
+                return false;
+            }
+
+            self.set_compare(cs, mono);
+
+            // The alarm may have slipped into the past while arming.
+            if timestamp <= mono.now().ticks() {
+                state.timestamp.set(u64::MAX);
+                return false;
+            }
+            true
+        })
+    }
+}
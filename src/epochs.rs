@@ -0,0 +1,61 @@
+//! Named reference points in time
+
+/// Number of named epochs a [`DwtSystick`](crate::DwtSystick) can hold.
+pub const MAX_EPOCHS: usize = 4;
+
+/// Extended cycle counts of the named epochs.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Epochs {
+    marks: [Option<(&'static str, u64)>; MAX_EPOCHS],
+}
+
+impl Epochs {
+    pub(crate) const fn new() -> Self {
+        Self {
+            marks: [None; MAX_EPOCHS],
+        }
+    }
+
+    /// Record `name` at the extended cycle count `cycles`, replacing an
+    /// existing epoch of the same name.
+    ///
+    /// Returns `false` if `name` is new and all slots are taken.
+    pub(crate) fn mark(&mut self, name: &'static str, cycles: u64) -> bool {
+        let slot = self
+            .marks
+            .iter()
+            .position(|m| matches!(m, Some((n, _)) if *n == name))
+            .or_else(|| self.marks.iter().position(Option::is_none));
+        match slot {
+            Some(index) => {
+                self.marks[index] = Some((name, cycles));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The extended cycle count of `name`.
+    pub(crate) fn get(&self, name: &str) -> Option<u64> {
+        self.marks
+            .iter()
+            .flatten()
+            .find(|(n, _)| *n == name)
+            .map(|&(_, cycles)| cycles)
+    }
+
+    /// Remove `name`, returning whether it was marked.
+    pub(crate) fn forget(&mut self, name: &str) -> bool {
+        match self
+            .marks
+            .iter_mut()
+            .find(|m| matches!(m, Some((n, _)) if *n == name))
+        {
+            Some(mark) => {
+                *mark = None;
+                true
+            }
+            None => false,
+        }
+    }
+}
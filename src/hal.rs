@@ -0,0 +1,286 @@
+//! Hardware abstraction for the counting and compare peripherals
+//!
+//! [`DwtSystick`](crate::DwtSystick) obtains time from a free-running
+//! up-counter and compare events from a [`CompareTimer`], by default a
+//! down-counter interrupting on zero. On Cortex-M these are the DWT cycle
+//! counter and SysTick. Implementing the traits below for other types (e.g.
+//! a software model or vendor timers) allows running the monotonic logic
+//! off-target or replacing the DWT and SysTick.
+
+#[cfg(not(armv6m))]
+use cortex_m::peripheral::DWT;
+use cortex_m::peripheral::{syst::SystClkSource, SCB, SYST};
+
+use crate::{CYCCNT_BITS, SYST_MAX_RELOAD, SYST_MIN_RELOAD};
+
+/// A free-running up-counter like the DWT cycle counter (`CYCCNT`).
+///
+/// The counter is [`BITS`](Self::BITS) wide and counts at the frequency of
+/// the monotonic (`TIMER_HZ`), its ticks are the "cycles" of
+/// [`DwtSystick`](crate::DwtSystick). It is extended in software by
+/// tracking its overflows, so the compare events are limited to a quarter of
+/// its period.
+///
+/// The compare timer runs at the counter rate or an integer fraction of it,
+/// a counter slower than the compare timer is not supported.
+pub trait CycleCounter {
+    /// Width of the counter in bits, from 3 to 32.
+    ///
+    /// Other widths fail to compile when instantiating a monotonic.
+    const BITS: u32 = CYCCNT_BITS;
+
+    /// Remove any software lock preventing writes to the counter.
+    fn unlock(&mut self) {}
+
+    /// Whether the software lock is still set after [`unlock()`](Self::unlock).
+    fn is_locked(&self) -> bool {
+        false
+    }
+
+    /// Whether the counter is implemented.
+    fn has_cycle_counter(&self) -> bool {
+        true
+    }
+
+    /// Set the current count.
+    fn set_cycle_count(&mut self, count: u32);
+
+    /// Start counting.
+    fn enable_cycle_counter(&mut self);
+
+    /// Stop counting, keeping the count.
+    fn disable_cycle_counter(&mut self);
+
+    /// The current count, less than `2**BITS`.
+    fn cycle_count(&self) -> u32;
+}
+
+/// A 24 bit down-counter with interrupt on zero like SysTick.
+///
+/// The counter reloads from the reload value when reaching zero and raises
+/// its exception.
+pub trait DownCounter {
+    /// Select the counter clock.
+    fn set_clock_source(&mut self, clk_source: SystClkSource);
+
+    /// Start counting.
+    fn enable_counter(&mut self);
+
+    /// Stop counting.
+    fn disable_counter(&mut self);
+
+    /// Set the value loaded when the counter reaches zero.
+    fn set_reload(&mut self, value: u32);
+
+    /// Clear the current count. This does not raise the exception and
+    /// loads the reload value on the next cycle.
+    fn clear_current(&mut self);
+
+    /// The current count.
+    fn current(&self) -> u32;
+
+    /// Whether the counter reached zero since the last check (`COUNTFLAG`).
+    ///
+    /// Reading clears the flag.
+    fn has_wrapped(&mut self) -> bool;
+
+    /// Raise the exception when reaching zero (`TICKINT`).
+    fn enable_interrupt(&mut self);
+
+    /// Stop raising the exception.
+    fn disable_interrupt(&mut self);
+
+    /// Clear a pending exception.
+    fn clear_pending(&mut self);
+}
+
+/// A timer generating the compare events.
+///
+/// Intervals are in timer ticks. [`DwtSystick`](crate::DwtSystick) converts
+/// cycles to ticks by the divisor set with
+/// [`DwtSystick::with_timer_divisor()`](crate::DwtSystick::with_timer_divisor),
+/// rounding up. Any [`DownCounter`] is a `CompareTimer` interrupting once the
+/// reload value has counted down.
+pub trait CompareTimer {
+    /// Longest interval in ticks that can be armed.
+    fn max_ticks(&self) -> u64;
+
+    /// Largest divisor of the cycle counter clock the timer can run at.
+    ///
+    /// By default any rate is supported.
+    fn max_divisor(&self) -> u32 {
+        u32::MAX
+    }
+
+    /// Start the timer and enable its interrupt without any compare event
+    /// armed.
+    fn start(&mut self);
+
+    /// Arm a compare event `ticks` (at most [`max_ticks()`](Self::max_ticks))
+    /// from now, replacing any armed event.
+    fn arm_relative(&mut self, ticks: u64);
+
+    /// Arm a compare event at tick `at` given the current tick `now`,
+    /// replacing any armed event.
+    ///
+    /// Timers with an absolute compare register can implement this free of
+    /// the race between reading `now` and arming. An event at an instant
+    /// already passed must still fire.
+    fn arm_absolute(&mut self, at: u64, now: u64) {
+        self.arm_relative(at.saturating_sub(now));
+    }
+
+    /// Acknowledge the compare event in its interrupt handler.
+    ///
+    /// Returns whether an armed interval has elapsed, `false` if the
+    /// interrupt was pended otherwise.
+    fn clear(&mut self) -> bool;
+
+    /// Disarm any compare event, e.g. when the monotonic timer is disabled.
+    ///
+    /// By default the event stays armed.
+    fn disarm(&mut self) {}
+
+    /// Stop the timer and any compare event when releasing it, including a
+    /// pending interrupt.
+    fn stop(&mut self);
+}
+
+impl<T: DownCounter> CompareTimer for T {
+    #[inline(always)]
+    fn max_ticks(&self) -> u64 {
+        SYST_MAX_RELOAD as u64
+    }
+
+    #[inline(always)]
+    fn start(&mut self) {
+        self.set_clock_source(SystClkSource::Core);
+        self.enable_interrupt();
+        self.enable_counter();
+    }
+
+    #[inline(always)]
+    fn arm_relative(&mut self, ticks: u64) {
+        // ARM Architecture Reference Manual says:
+        // "Setting SYST_RVR to zero has the effect of
+        // disabling the SysTick counter independently
+        // of the counter enable bit.", so the min is 1
+        self.set_reload(ticks.clamp(SYST_MIN_RELOAD as u64, SYST_MAX_RELOAD as u64) as u32);
+        // Also clear the current counter. That doesn't cause an
+        // interrupt and loads the reload value on the next cycle.
+        self.clear_current();
+    }
+
+    #[inline(always)]
+    fn clear(&mut self) -> bool {
+        self.has_wrapped()
+    }
+
+    #[inline(always)]
+    fn disarm(&mut self) {
+        // A zero reload value stops the counter at zero until re-armed.
+        self.set_reload(0);
+        self.clear_current();
+    }
+
+    #[inline(always)]
+    fn stop(&mut self) {
+        self.disable_counter();
+        self.disable_interrupt();
+        self.clear_pending();
+    }
+}
+
+#[cfg(not(armv6m))]
+impl CycleCounter for DWT {
+    #[inline(always)]
+    fn unlock(&mut self) {
+        DWT::unlock();
+    }
+
+    #[inline(always)]
+    fn is_locked(&self) -> bool {
+        // Software lock implemented (SLI) and locked (SLK)
+        const LSR_SLI_SLK: u32 = 0b11;
+
+        // NOTE(unsafe) atomic read with no side effects
+        unsafe { (*DWT::PTR).lsr.read() & LSR_SLI_SLK == LSR_SLI_SLK }
+    }
+
+    #[inline(always)]
+    fn has_cycle_counter(&self) -> bool {
+        DWT::has_cycle_counter()
+    }
+
+    #[inline(always)]
+    fn set_cycle_count(&mut self, count: u32) {
+        DWT::set_cycle_count(self, count);
+    }
+
+    #[inline(always)]
+    fn enable_cycle_counter(&mut self) {
+        DWT::enable_cycle_counter(self);
+    }
+
+    #[inline(always)]
+    fn disable_cycle_counter(&mut self) {
+        DWT::disable_cycle_counter(self);
+    }
+
+    #[inline(always)]
+    fn cycle_count(&self) -> u32 {
+        DWT::cycle_count()
+    }
+}
+
+impl DownCounter for SYST {
+    #[inline(always)]
+    fn set_clock_source(&mut self, clk_source: SystClkSource) {
+        SYST::set_clock_source(self, clk_source);
+    }
+
+    #[inline(always)]
+    fn enable_counter(&mut self) {
+        SYST::enable_counter(self);
+    }
+
+    #[inline(always)]
+    fn disable_counter(&mut self) {
+        SYST::disable_counter(self);
+    }
+
+    #[inline(always)]
+    fn set_reload(&mut self, value: u32) {
+        SYST::set_reload(self, value);
+    }
+
+    #[inline(always)]
+    fn clear_current(&mut self) {
+        SYST::clear_current(self);
+    }
+
+    #[inline(always)]
+    fn current(&self) -> u32 {
+        SYST::get_current()
+    }
+
+    #[inline(always)]
+    fn has_wrapped(&mut self) -> bool {
+        SYST::has_wrapped(self)
+    }
+
+    #[inline(always)]
+    fn enable_interrupt(&mut self) {
+        SYST::enable_interrupt(self);
+    }
+
+    #[inline(always)]
+    fn disable_interrupt(&mut self) {
+        SYST::disable_interrupt(self);
+    }
+
+    #[inline(always)]
+    fn clear_pending(&mut self) {
+        SCB::clear_pendst();
+    }
+}
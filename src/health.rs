@@ -0,0 +1,131 @@
+//! Overflow tracking health monitor
+
+/// Overflow tracking health, see [`DwtSystick::health()`](crate::DwtSystick::health).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Health {
+    /// Number of times the cycle counter was not observed for longer than
+    /// half its period, risking a missed overflow.
+    pub late: u32,
+    /// Number of detected missed overflows.
+    pub missed: u32,
+    /// Longest time in cycles the cycle counter was not observed.
+    pub max_gap: u64,
+}
+
+impl Health {
+    /// No late observation and no missed overflow so far.
+    pub fn is_ok(&self) -> bool {
+        self.late == 0 && self.missed == 0
+    }
+}
+
+/// Anomaly passed to the health hook.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Anomaly {
+    /// The cycle counter was not observed for `gap` cycles, more than half
+    /// its period.
+    Late {
+        /// Cycles since the previous observation.
+        gap: u64,
+    },
+    /// Time is `deficit` cycles short of the elapsed SysTick period or went
+    /// backwards: at least one overflow was missed.
+    MissedOverflow {
+        /// Cycles missing.
+        deficit: u64,
+    },
+}
+
+/// Cross-checks the extended cycle count against the SysTick periods.
+///
+/// A SysTick wrap implies that at least the programmed period has elapsed
+/// since it was armed. This is independent of the cycle counter overflow
+/// tracking and catches time jumping back by a multiple of the counter period.
+pub(crate) struct Monitor {
+    health: Health,
+    /// Last observed extended cycle count.
+    last: u64,
+    /// Cycles the cycle counter may go unobserved without risking a missed
+    /// overflow.
+    max_gap: u64,
+    /// Cycle count when SysTick was armed and the cycles until it wraps.
+    armed_at: u64,
+    armed: u64,
+    hook: Option<fn(Anomaly)>,
+}
+
+impl Monitor {
+    pub(crate) const fn new(max_gap: u64) -> Self {
+        Self {
+            health: Health {
+                late: 0,
+                missed: 0,
+                max_gap: 0,
+            },
+            last: 0,
+            max_gap,
+            armed_at: 0,
+            armed: 0,
+            hook: None,
+        }
+    }
+
+    pub(crate) fn health(&self) -> Health {
+        self.health
+    }
+
+    pub(crate) fn set_hook(&mut self, hook: fn(Anomaly)) {
+        self.hook = Some(hook);
+    }
+
+    /// Continue from the extended cycle count `now` after a gap, e.g. when
+    /// resuming.
+    pub(crate) fn restart(&mut self, now: u64) {
+        self.last = now;
+        self.armed_at = now;
+        self.armed = 0;
+    }
+
+    /// Record an observation of the extended cycle count.
+    pub(crate) fn observe(&mut self, now: u64) {
+        match now.checked_sub(self.last) {
+            Some(gap) => {
+                self.health.max_gap = self.health.max_gap.max(gap);
+                if gap > self.max_gap {
+                    self.health.late += 1;
+                    self.report(Anomaly::Late { gap });
+                }
+            }
+            None => {
+                self.health.missed += 1;
+                self.report(Anomaly::MissedOverflow {
+                    deficit: self.last - now,
+                });
+            }
+        }
+        self.last = now;
+    }
+
+    /// SysTick was armed at the last observation to wrap after `cycles`.
+    pub(crate) fn arm(&mut self, cycles: u64) {
+        self.armed_at = self.last;
+        self.armed = cycles;
+    }
+
+    /// SysTick wrapped, check the last observation against the armed period.
+    pub(crate) fn wrapped(&mut self) {
+        let expected = self.armed_at + self.armed;
+        if self.last < expected {
+            self.health.missed += 1;
+            self.report(Anomaly::MissedOverflow {
+                deficit: expected - self.last,
+            });
+        }
+    }
+
+    fn report(&self, anomaly: Anomaly) {
+        if let Some(hook) = self.hook {
+            hook(anomaly);
+        }
+    }
+}
@@ -1,14 +1,416 @@
 //! # `Monotonic` implementation based on DWT cycle counter and SysTick
 
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
-use cortex_m::peripheral::{syst::SystClkSource, DCB, DWT, SYST};
+mod builder;
+#[cfg(not(armv6m))]
+mod clock;
+#[cfg(not(armv6m))]
+mod comparator;
+mod deadline;
+#[cfg(all(feature = "embedded-hal", not(armv6m)))]
+pub mod delay;
+#[cfg(all(feature = "embassy", not(armv6m)))]
+pub mod embassy;
+mod epochs;
+pub mod hal;
+mod health;
+#[cfg(all(feature = "rtic2", not(armv6m)))]
+pub mod rtic2;
+mod runtime;
+#[cfg(any(test, feature = "sim"))]
+pub mod sim;
+mod systick;
+#[cfg(test)]
+mod tests;
+mod wakeup;
+
+pub use builder::Builder;
+#[cfg(not(armv6m))]
+pub use clock::Clock;
+#[cfg(not(armv6m))]
+pub use comparator::{Comparator, DwtComparator};
+use core::{
+    marker::PhantomData,
+    sync::atomic::{AtomicU32, Ordering},
+};
+#[cfg(not(armv6m))]
+use cortex_m::peripheral::DCB;
+use cortex_m::peripheral::{syst::SystClkSource, DWT, SYST};
+pub use deadline::DeadlineMisses;
+use deadline::DeadlineMonitor;
+use epochs::Epochs;
+pub use epochs::MAX_EPOCHS;
 pub use fugit;
+pub use fugit::{ExtU32, ExtU64};
 #[cfg(not(feature = "extend"))]
-pub use fugit::{ExtU32, TimerDurationU32 as TimerDuration, TimerInstantU32 as TimerInstant};
+pub use fugit::{TimerDurationU32 as TimerDuration, TimerInstantU32 as TimerInstant};
 #[cfg(feature = "extend")]
-pub use fugit::{ExtU64, TimerDurationU64 as TimerDuration, TimerInstantU64 as TimerInstant};
+pub use fugit::{TimerDurationU64 as TimerDuration, TimerInstantU64 as TimerInstant};
+use hal::{CompareTimer, CycleCounter, DownCounter};
+use health::Monitor;
+pub use health::{Anomaly, Health};
 use rtic_monotonic::Monotonic;
+pub use runtime::RuntimeDwtSystick;
+pub use systick::SystickOnly;
+use wakeup::Wakeup;
+
+/// Default width of the instants, `u64` with the `extend` feature and `u32`
+/// otherwise.
+///
+/// Prefer naming the width explicitly (e.g. [`DwtSystick32`] or
+/// [`DwtSystick64`]) since cargo features are unified across the dependency
+/// graph.
+#[cfg(not(feature = "extend"))]
+pub type Ticks = u32;
+#[cfg(feature = "extend")]
+pub type Ticks = u64;
+
+/// SysTick is a 24 bit counter.
+const SYST_MAX_RELOAD: u32 = 0xff_ffff;
+
+/// Setting the SysTick reload value to zero disables it.
+const SYST_MIN_RELOAD: u32 = 1;
+
+/// Width of the DWT cycle counter.
+const CYCCNT_BITS: u32 = 32;
+
+/// Longest compare interval in cycles of a `bits` wide counter, keeping the
+/// overflow tracking interrupts within a quarter of its period.
+#[inline(always)]
+const fn max_interval(bits: u32) -> u64 {
+    1 << (bits - 2)
+}
+
+/// Check a timer divisor against the timer and the cycle counter width.
+fn check_divisor(divisor: u32, max_divisor: u32, bits: u32) -> Result<(), Error> {
+    // The longest compare interval must be at least a tick, see `range()`.
+    if divisor == 0 || divisor > max_divisor || max_interval(bits) / (divisor as u64) < 2 {
+        return Err(Error::InvalidDivisor(divisor));
+    }
+    Ok(())
+}
+
+/// Configuration errors when creating a [`DwtSystick`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Error {
+    /// The declared frequency does not match the actual core clock.
+    FrequencyMismatch {
+        /// The declared frequency (generic parameter `TIMER_HZ`).
+        timer_hz: u32,
+        /// The actual core clock.
+        sysclk: u32,
+    },
+    /// The DWT does not implement a cycle counter.
+    NoCycleCounter,
+    /// The global trace enable (`DEMCR.TRCENA`) did not stick.
+    TraceNotEnabled,
+    /// The DWT software lock could not be removed.
+    DwtLocked,
+    /// The DWT has no comparator matching the cycle counter.
+    NoComparator,
+    /// The core clock or the unit frequency is zero.
+    ZeroFrequency,
+    /// The SysTick clock divisor is zero or larger than [`SYST_MAX_DIVISOR`],
+    /// or a timer divisor is zero, not supported by the timer or longer than
+    /// an eighth of the cycle counter period.
+    InvalidDivisor(u32),
+    /// All [`MAX_EPOCHS`] named epochs are taken.
+    TooManyEpochs,
+    /// The minimum SysTick reload value is zero or larger than the maximum.
+    InvalidReload(u32),
+}
+
+/// A configuration [`Error`] together with the peripherals passed to a
+/// `try_` constructor, e.g. to fall back to another monotonic.
+///
+/// Converts into the [`Error`] for `?`.
+pub struct Rejected<C, T> {
+    /// Why the monotonic could not be created.
+    pub error: Error,
+    /// The cycle counter, possibly unlocked.
+    pub counter: C,
+    /// The compare timer, stopped.
+    pub timer: T,
+}
+
+impl<C, T> core::fmt::Debug for Rejected<C, T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Rejected")
+            .field("error", &self.error)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<C, T> From<Rejected<C, T>> for Error {
+    fn from(rejected: Rejected<C, T>) -> Self {
+        rejected.error
+    }
+}
+
+/// Largest SysTick clock divisor.
+///
+/// This keeps the longest SysTick period at a quarter of the cycle counter
+/// period so that overflows can still be tracked.
+pub const SYST_MAX_DIVISOR: u32 = 64;
+
+/// SysTick clock source and rate relative to the cycle counter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SystickClock {
+    /// The core clock, i.e. the cycle counter clock.
+    Core,
+    /// The external reference clock, running at the core clock divided by `divisor`.
+    External {
+        /// The ratio of the core clock to the SysTick clock.
+        divisor: u32,
+    },
+}
+
+/// Origin of the instants of [`DwtSystick`], see [`DwtSystick::with_epoch`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Epoch {
+    /// Time counts from the start of the cycle counter. `reset()` only
+    /// records the origin of `adjusted_now()`.
+    #[default]
+    Boot,
+    /// Time starts at zero at `reset()`. `now()` and `set_compare()` are
+    /// relative to it, like `adjusted_now()`.
+    Reset,
+}
+
+/// How the overflows of the cycle counter are tracked, see
+/// [`Builder::overflow_tracking`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowTracking {
+    /// The compare interrupt is re-armed to fire at least every quarter of
+    /// the cycle counter period.
+    #[default]
+    Interrupt,
+    /// The application observes the cycle counter (e.g. calls `now()`) at
+    /// least every quarter of its period, so the compare interrupt only
+    /// fires for scheduled instants. The compare timer is disarmed in
+    /// between.
+    Observed,
+}
+
+impl SystickClock {
+    /// The SysTick clock source and divisor, checking the divisor.
+    fn source(self) -> Result<(SystClkSource, u32), Error> {
+        let (source, divisor) = match self {
+            SystickClock::Core => (SystClkSource::Core, 1),
+            SystickClock::External { divisor } => (SystClkSource::External, divisor),
+        };
+        if !(1..=SYST_MAX_DIVISOR).contains(&divisor) {
+            return Err(Error::InvalidDivisor(divisor));
+        }
+        Ok((source, divisor))
+    }
+}
+
+/// Check the declared frequency against the actual core clock.
+fn check_frequency(timer_hz: u32, sysclk: u32) -> Result<(), Error> {
+    if timer_hz == sysclk {
+        Ok(())
+    } else {
+        Err(Error::FrequencyMismatch { timer_hz, sysclk })
+    }
+}
+
+/// Enable the global trace required by the DWT.
+#[cfg(not(armv6m))]
+fn enable_trace(dcb: &mut DCB) -> Result<(), Error> {
+    const DCB_DEMCR_TRCENA: u32 = 1 << 24;
+
+    dcb.enable_trace();
+    if dcb.demcr.read() & DCB_DEMCR_TRCENA != 0 {
+        Ok(())
+    } else {
+        Err(Error::TraceNotEnabled)
+    }
+}
+
+/// Enable the cycle counter and the compare timer, setting the cycle count
+/// to `count` if given.
+fn start<C: CycleCounter, T: CompareTimer>(
+    counter: &mut C,
+    timer: &mut T,
+    count: Option<u32>,
+) -> Result<(), Error> {
+    prepare_counter(counter, count)?;
+
+    // Start the counter
+    timer.start();
+    counter.enable_cycle_counter();
+    Ok(())
+}
+
+/// Unlock and check the cycle counter and set it to `count` if given.
+fn prepare_counter<C: CycleCounter>(counter: &mut C, count: Option<u32>) -> Result<(), Error> {
+    counter.unlock();
+    if counter.is_locked() {
+        return Err(Error::DwtLocked);
+    }
+    if !counter.has_cycle_counter() {
+        return Err(Error::NoCycleCounter);
+    }
+
+    if let Some(count) = count {
+        counter.set_cycle_count(count);
+    }
+    Ok(())
+}
+
+/// SysTick reload value to obtain a compare event `ticks` cycles from now
+/// with SysTick running at the cycle counter clock divided by `divisor`.
+#[inline(always)]
+fn systick_reload(ticks: impl Into<u64>, divisor: u32) -> u32 {
+    ticks
+        .into()
+        // Round up so the compare event never fires early.
+        .div_ceil(divisor as u64)
+        // ARM Architecture Reference Manual says:
+        // "Setting SYST_RVR to zero has the effect of
+        // disabling the SysTick counter independently
+        // of the counter enable bit.", so the min is 1
+        .clamp(SYST_MIN_RELOAD as u64, SYST_MAX_RELOAD as u64) as u32
+}
+
+/// Lock-free extension of a `bits` wide cycle counter to `u64`.
+///
+/// `period` counts the half periods (`2**(bits - 1)` cycles) of the cycle
+/// counter. Its parity matches the most significant bit of the cycle counter
+/// once it is up to date. It is only ever advanced by a single context (the owner of
+/// the monotonic) which needs to observe the counter at least once per half
+/// period. It then lags the true half period count `h` by at most one.
+///
+/// Any other context can extend a cycle count read *after* loading `period`:
+/// with `period` being `h - 1` or `h` the count is less than `2**bits` cycles
+/// ahead of `period * 2**(bits - 1)` and thus unambiguous. There is no
+/// read-modify-write shared between contexts, so preemption at any point
+/// can neither tear nor double count the extension.
+#[derive(Debug)]
+struct Overflow {
+    period: AtomicU32,
+}
+
+impl Overflow {
+    const fn new() -> Self {
+        Self {
+            period: AtomicU32::new(0),
+        }
+    }
+
+    /// Extend `now` given the half period count loaded before it was read.
+    #[inline(always)]
+    fn extend(period: u32, now: u32, bits: u32) -> u64 {
+        let half = bits - 1;
+        let mask = u32::MAX >> (32 - bits);
+        ((period as u64) << half) + (now.wrapping_sub(period << half) & mask) as u64
+    }
+
+    /// Advance the half period count to the cycle count `now` and extend it.
+    ///
+    /// Must only be called by the single owning context.
+    #[inline(always)]
+    fn update(&self, now: u32, bits: u32) -> u64 {
+        let mut period = self.period.load(Ordering::Relaxed);
+        if (now >> (bits - 1)) & 1 != period & 1 {
+            period = period.wrapping_add(1);
+            self.period.store(period, Ordering::Release);
+        }
+        Self::extend(period, now, bits)
+    }
+
+    /// Extend the cycle count obtained from `read` in any context.
+    #[cfg_attr(armv6m, allow(dead_code))]
+    #[inline(always)]
+    fn read(&self, read: impl FnOnce() -> u32, bits: u32) -> u64 {
+        let period = self.period.load(Ordering::Acquire);
+        Self::extend(period, read(), bits)
+    }
+
+    /// Continue from the extended cycle count `cycles`.
+    fn set(&self, cycles: u64, bits: u32) {
+        self.period
+            .store((cycles >> (bits - 1)) as u32, Ordering::Release);
+    }
+}
+
+/// Extended cycle count at the origin of the instants, shared lock-free.
+///
+/// The 64 bit value is double buffered in two pairs of words: the single
+/// owning context writes the pair not selected by `generation` and then
+/// advances it. A reader preempting the writer reads the other, complete,
+/// pair. A reader preempted by the writer sees `generation` change and
+/// retries, which terminates as the writer ran to completion.
+#[cfg_attr(armv6m, allow(dead_code))]
+#[derive(Debug)]
+struct Origin {
+    generation: AtomicU32,
+    cycles: [[AtomicU32; 2]; 2],
+}
+
+#[cfg_attr(armv6m, allow(dead_code))]
+impl Origin {
+    const fn new() -> Self {
+        Self {
+            generation: AtomicU32::new(0),
+            cycles: [
+                [AtomicU32::new(0), AtomicU32::new(0)],
+                [AtomicU32::new(0), AtomicU32::new(0)],
+            ],
+        }
+    }
+
+    /// Publish the origin `cycles`.
+    ///
+    /// Must only be called by the single owning context.
+    fn set(&self, cycles: u64) {
+        let generation = self.generation.load(Ordering::Relaxed).wrapping_add(1);
+        let [low, high] = &self.cycles[generation as usize & 1];
+        low.store(cycles as u32, Ordering::Relaxed);
+        high.store((cycles >> 32) as u32, Ordering::Relaxed);
+        self.generation.store(generation, Ordering::Release);
+    }
+
+    /// The origin in any context.
+    fn read(&self) -> u64 {
+        loop {
+            let generation = self.generation.load(Ordering::Acquire);
+            let [low, high] = &self.cycles[generation as usize & 1];
+            let cycles =
+                (high.load(Ordering::Relaxed) as u64) << 32 | low.load(Ordering::Relaxed) as u64;
+            // Order the loads of the pair before checking it was not rewritten.
+            core::sync::atomic::fence(Ordering::Acquire);
+            if self.generation.load(Ordering::Relaxed) == generation {
+                return cycles;
+            }
+        }
+    }
+}
+
+/// Overflow state of the DWT cycle counter.
+///
+/// There is only one DWT. Its extension is published by the owning
+/// [`DwtSystick`] for the [`Clock`] handles, or kept by the RTIC v2 backend.
+#[cfg(not(armv6m))]
+static CYCCNT: Overflow = Overflow::new();
+
+/// Origin of the instants of the [`DwtSystick`] publishing [`CYCCNT`].
+#[cfg(not(armv6m))]
+static ORIGIN: Origin = Origin::new();
+
+/// Cycles at `timer_hz` in `value` units of `1 / per_second` seconds, rounded up.
+#[cfg(all(
+    any(feature = "embedded-hal", feature = "embedded-hal-async"),
+    not(armv6m)
+))]
+#[inline(always)]
+fn cycles_at_least(value: u32, per_second: u64, timer_hz: u32) -> u64 {
+    // Can't overflow: (2**32 - 1)**2 < 2**64
+    (value as u64 * timer_hz as u64).div_ceil(per_second)
+}
 
 /// DWT and Systick combination implementing `rtic_monotonic::Monotonic`.
 ///
@@ -16,148 +418,715 @@ use rtic_monotonic::Monotonic;
 /// "ticks" (like `systick-monotonic`) but only to obtain actual desired compare
 /// events and to manage overflows.
 ///
-/// The frequency of the DWT and SysTick is encoded using the parameter `TIMER_HZ`.
-/// They must be equal.
+/// The frequency of the DWT cycle counter is encoded using the parameter
+/// `TIMER_HZ`. SysTick runs at that frequency divided by an integer divisor,
+/// 1 by default, see [`DwtSystick::with_systick_clock`] and
+/// [`DwtSystick::with_timer_divisor`].
+///
+/// Note that the SysTick interrupt must not be disabled longer than a quarter
+/// of the cycle counter overflow period (typically a second).
 ///
-/// Note that the SysTick interrupt must not be disabled longer than half the
-/// cycle counter overflow period (typically a couple seconds).
+/// The width `W` of the instants is `u32` ([`DwtSystick32`]) or `u64`
+/// ([`DwtSystick64`]). For `u64` the cycle counter width is extended by
+/// detecting and counting overflows. The default is [`Ticks`], i.e. `u64`
+/// only when the `extend` feature is enabled.
 ///
-/// When the `extend` feature is enabled, the cycle counter width is extended to
-/// `u64` by detecting and counting overflows.
-pub struct DwtSystick<const TIMER_HZ: u32> {
-    systick: SYST,
-    cycle_offset: TimerInstant<TIMER_HZ>,
-    #[cfg(feature = "extend")]
-    last: u64,
+/// The peripherals are accessed through the [`hal`] traits and default to
+/// the Cortex-M `DWT` and `SYST`. Any [`CompareTimer`]
+/// can replace SysTick, e.g. a wider vendor timer for longer compare
+/// intervals, and any [`CycleCounter`] running at `TIMER_HZ` can replace the
+/// DWT, e.g. a 16 bit vendor timer where the DWT is unavailable, see
+/// [`DwtSystick::from_parts`]. Counters narrower than the instants are
+/// extended like for `u64`.
+///
+/// On cores without cycle counter (ARMv6-M) use [`SystickOnly`] instead.
+pub struct DwtSystick<const TIMER_HZ: u32, C = DWT, T = SYST, W = Ticks> {
+    counter: C,
+    timer: T,
+    /// Cycles per compare timer tick.
+    divisor: u32,
+    /// Shortest compare interval in timer ticks.
+    min_ticks: u64,
+    tracking: OverflowTracking,
+    epoch: Epoch,
+    overflow: Overflow,
+    /// Whether the overflow state is published to [`Clock`] handles.
+    publish: bool,
+    /// Extended cycle count at `reset()`.
+    cycle_offset: u64,
+    epochs: Epochs,
+    monitor: Monitor,
+    deadlines: DeadlineMonitor,
+    wakeup: Wakeup,
+    _width: PhantomData<W>,
+}
+
+/// Time of a released [`DwtSystick`], see [`DwtSystick::release`].
+#[derive(Clone, Copy, Debug)]
+pub struct Suspended {
+    /// Extended cycle count at the release.
+    cycles: u64,
+    /// Whether the cycle counter was left running.
+    counting: bool,
+    publish: bool,
+    epoch: Epoch,
+    cycle_offset: u64,
+    epochs: Epochs,
 }
 
-impl<const TIMER_HZ: u32> DwtSystick<TIMER_HZ> {
+/// [`DwtSystick`] with `u32` instants wrapping with the cycle counter.
+pub type DwtSystick32<const TIMER_HZ: u32, C = DWT, T = SYST> = DwtSystick<TIMER_HZ, C, T, u32>;
+
+/// [`DwtSystick`] with `u64` instants extending the cycle counter.
+pub type DwtSystick64<const TIMER_HZ: u32, C = DWT, T = SYST> = DwtSystick<TIMER_HZ, C, T, u64>;
+
+#[cfg(not(armv6m))]
+impl<const TIMER_HZ: u32, W> DwtSystick<TIMER_HZ, DWT, SYST, W> {
     /// Enable the DWT and provide a new `Monotonic` based on DWT and SysTick.
     ///
     /// Note that the `sysclk` parameter should come from e.g. the HAL's clock generation function
     /// so the speed calculated at runtime and the declared speed (generic parameter
     /// `TIMER_HZ`) can be compared.
+    ///
+    /// # Panics
+    ///
+    /// On any configuration [`Error`], see [`DwtSystick::try_new`].
     #[inline(always)]
-    pub fn new(dcb: &mut DCB, mut dwt: DWT, mut systick: SYST, sysclk: u32) -> Self {
-        assert!(TIMER_HZ == sysclk);
+    pub fn new(dcb: &mut DCB, dwt: DWT, systick: SYST, sysclk: u32) -> Self {
+        Self::try_new(dcb, dwt, systick, sysclk).unwrap()
+    }
+
+    /// Enable the DWT and provide a new `Monotonic` based on DWT and SysTick.
+    ///
+    /// Like [`DwtSystick::new`] but returns an [`Error`] together with the
+    /// peripherals if the configuration is invalid or the DWT can not be enabled.
+    pub fn try_new(
+        dcb: &mut DCB,
+        dwt: DWT,
+        systick: SYST,
+        sysclk: u32,
+    ) -> Result<Self, Rejected<DWT, SYST>> {
+        if let Err(error) = check_frequency(TIMER_HZ, sysclk).and_then(|()| enable_trace(dcb)) {
+            return Err(Rejected {
+                error,
+                counter: dwt,
+                timer: systick,
+            });
+        }
+        Self::try_from_parts(dwt, systick, sysclk)
+    }
+}
+
+#[cfg(not(armv6m))]
+impl<const TIMER_HZ: u32, T: CompareTimer, W> DwtSystick<TIMER_HZ, DWT, T, W> {
+    /// A `Copy` handle reading the current time from any context.
+    ///
+    /// From now on the monotonic publishes its overflow state and the origin
+    /// of its instants for the handles, also after [`DwtSystick::release`]
+    /// and resuming.
+    pub fn clock(&mut self) -> Clock<TIMER_HZ, W> {
+        if !self.publish {
+            let now = self.count();
+            CYCCNT.set(now, CYCCNT_BITS);
+            self.publish = true;
+            self.publish_origin();
+        }
+        // NOTE(unsafe) The cycle counter was enabled by `new()` and its
+        // overflow state and origin are published.
+        unsafe { Clock::new(&CYCCNT, &ORIGIN) }
+    }
+
+    /// A busy-waiting `embedded_hal::delay::DelayNs` provider reading the
+    /// running cycle counter.
+    #[cfg(feature = "embedded-hal")]
+    pub fn delay(&self) -> delay::Delay<TIMER_HZ> {
+        // NOTE(unsafe) The cycle counter was enabled by `new()`.
+        unsafe { delay::Delay::new() }
+    }
+}
 
-        dcb.enable_trace();
-        DWT::unlock();
-        assert!(DWT::has_cycle_counter());
+impl<const TIMER_HZ: u32, C: CycleCounter, T: CompareTimer, W> DwtSystick<TIMER_HZ, C, T, W> {
+    /// Whether the cycle counter is narrower than the instants and its
+    /// overflows need to be tracked.
+    const EXTEND: bool = C::BITS < 32 || core::mem::size_of::<W>() > 4;
 
+    /// Rejects cycle counter widths the overflow extension can not handle
+    /// when the monotonic is instantiated.
+    const VALID_BITS: () = assert!(
+        C::BITS >= 3 && C::BITS <= 32,
+        "CycleCounter::BITS must be from 3 to 32"
+    );
+
+    /// Provide a new `Monotonic` from a cycle counter and a compare timer.
+    ///
+    /// This is [`DwtSystick::new`] for arbitrary [`hal`] implementations.
+    /// Any global enable the counter depends on (like `DCB` trace enable)
+    /// must be set up by the caller.
+    ///
+    /// The timer must run at the counter rate, or at an integer fraction of
+    /// it set with [`DwtSystick::with_timer_divisor`]. A counter slower than
+    /// the timer, e.g. a 32 kHz low-power counter with SysTick compares, is
+    /// not supported. A [`CycleCounter::BITS`] outside 3 to 32 fails to
+    /// compile.
+    ///
+    /// # Panics
+    ///
+    /// On any configuration [`Error`], see [`DwtSystick::try_from_parts`].
+    #[inline(always)]
+    pub fn from_parts(counter: C, timer: T, sysclk: u32) -> Self {
+        Self::try_from_parts(counter, timer, sysclk).unwrap()
+    }
+
+    /// Provide a new `Monotonic` from a cycle counter and a compare timer.
+    ///
+    /// Like [`DwtSystick::from_parts`] but returns an [`Error`] together with the
+    /// peripherals if the configuration is invalid or the counter can not be enabled.
+    pub fn try_from_parts(counter: C, timer: T, sysclk: u32) -> Result<Self, Rejected<C, T>> {
+        if let Err(error) = check_frequency(TIMER_HZ, sysclk) {
+            return Err(Rejected {
+                error,
+                counter,
+                timer,
+            });
+        }
         // Clear the cycle counter here so scheduling (`set_compare()`) before `reset()`
         // works correctly.
-        dwt.set_cycle_count(0);
+        Self::try_start(counter, timer, true)
+    }
 
-        systick.set_clock_source(SystClkSource::Core);
+    /// Start the counters, continuing from the current cycle count unless
+    /// `zero`.
+    fn try_start(mut counter: C, mut timer: T, zero: bool) -> Result<Self, Rejected<C, T>> {
+        if let Err(error) = start(&mut counter, &mut timer, zero.then_some(0)) {
+            return Err(Rejected {
+                error,
+                counter,
+                timer,
+            });
+        }
+        let count = counter.cycle_count() as u64;
+
+        let mut mono = Self::assemble(counter, timer);
+        mono.overflow.set(count, C::BITS);
+        mono.monitor.restart(count);
+        // Interrupt within the longest compare interval even if no compare
+        // is ever set, tracking the overflows.
+        mono.arm(count, u64::MAX);
+        Ok(mono)
+    }
 
-        // Start the counter
-        systick.enable_counter();
-        dwt.enable_cycle_counter();
+    /// Provide a `Monotonic` from the peripherals and time returned by
+    /// [`DwtSystick::release`], continuing its time.
+    ///
+    /// The configuration (e.g. [`DwtSystick::with_systick_clock`] or the lead
+    /// time) is not carried over and needs to be applied again. The longest
+    /// compare interval is armed so the interrupt handler sets the next
+    /// compare event.
+    ///
+    /// # Panics
+    ///
+    /// On any configuration [`Error`], see [`DwtSystick::try_resume`].
+    #[inline(always)]
+    pub fn resume(counter: C, timer: T, sysclk: u32, suspended: Suspended) -> Self {
+        Self::try_resume(counter, timer, sysclk, suspended).unwrap()
+    }
 
+    /// Provide a `Monotonic` from the peripherals and time returned by
+    /// [`DwtSystick::release`], continuing its time.
+    ///
+    /// Like [`DwtSystick::resume`] but returns an [`Error`] together with the
+    /// peripherals if the configuration is invalid or the counter can not be enabled.
+    pub fn try_resume(
+        mut counter: C,
+        mut timer: T,
+        sysclk: u32,
+        suspended: Suspended,
+    ) -> Result<Self, Rejected<C, T>> {
+        // A stopped counter continues from the released count even if it
+        // was changed meanwhile.
+        let count =
+            (!suspended.counting).then_some(suspended.cycles as u32 & (u32::MAX >> (32 - C::BITS)));
+        if let Err(error) =
+            check_frequency(TIMER_HZ, sysclk).and_then(|()| start(&mut counter, &mut timer, count))
+        {
+            return Err(Rejected {
+                error,
+                counter,
+                timer,
+            });
+        }
+        let mut mono = Self::assemble(counter, timer);
+        mono.overflow.set(suspended.cycles, C::BITS);
+        mono.epoch = suspended.epoch;
+        mono.cycle_offset = suspended.cycle_offset;
+        mono.epochs = suspended.epochs;
+        let now = mono.overflow.update(mono.counter.cycle_count(), C::BITS);
+        mono.publish = suspended.publish;
+        #[cfg(not(armv6m))]
+        if mono.publish {
+            CYCCNT.set(now, C::BITS);
+        }
+        mono.publish_origin();
+        mono.monitor.restart(now);
+        // Have the interrupt handler re-arm any pending compare event.
+        mono.arm(now, u64::MAX);
+        Ok(mono)
+    }
+
+    fn assemble(counter: C, timer: T) -> Self {
+        let () = Self::VALID_BITS;
         DwtSystick {
-            systick,
-            cycle_offset: TimerInstant::from_ticks(0),
-            #[cfg(feature = "extend")]
-            last: 0,
+            counter,
+            timer,
+            divisor: 1,
+            min_ticks: 0,
+            tracking: OverflowTracking::Interrupt,
+            epoch: Epoch::Boot,
+            overflow: Overflow::new(),
+            publish: false,
+            cycle_offset: 0,
+            epochs: Epochs::new(),
+            // Overflows are missed once the counter goes unobserved for half
+            // its period, the compare intervals leave the other quarter for
+            // latency and the timer resolution.
+            monitor: Monitor::new(max_interval(C::BITS) * 2),
+            deadlines: DeadlineMonitor::new(),
+            wakeup: Wakeup::new(),
+            _width: PhantomData,
         }
     }
 
-    pub fn unadjusted_now(&mut self) -> TimerInstant<TIMER_HZ> {
-        cfg_if::cfg_if! {
-            if #[cfg(not(feature = "extend"))] {
-                TimerInstant::from_ticks(DWT::cycle_count())
-            } else {
-                let mut high = (self.last >> 32) as u32;
-                let low = self.last as u32;
-                let now = DWT::cycle_count();
+    /// Stop the compare events and return the peripherals, leaving the cycle
+    /// counter running.
+    ///
+    /// Use [`DwtSystick::release`] to resume the monotonic later.
+    pub fn free(self) -> (C, T) {
+        let (counter, timer, _) = self.release(true);
+        (counter, timer)
+    }
 
-                // Detect CYCCNT overflow
-                if now < low {
-                    high = high.wrapping_add(1);
-                }
-                self.last = ((high as u64) << 32) | (now as u64);
+    /// Stop the compare events and return the peripherals together with the
+    /// time to resume from with [`DwtSystick::resume`].
+    ///
+    /// With `keep_counting` the cycle counter keeps running, e.g. for
+    /// profiling, and the time spent released is included when resuming.
+    /// Its overflows are not tracked meanwhile, so the monotonic must be
+    /// resumed within half the cycle counter period. Otherwise the cycle
+    /// counter is stopped and time pauses until resumed.
+    ///
+    /// `Clock` handles obtained before remain usable but are not kept up to
+    /// date while released: their `u64` instants jump back by a cycle
+    /// counter period once it runs for more than half a period unresumed.
+    pub fn release(mut self, keep_counting: bool) -> (C, T, Suspended) {
+        self.timer.stop();
+        let cycles = self.cycles();
+        if !keep_counting {
+            self.counter.disable_cycle_counter();
+        }
+        let suspended = Suspended {
+            cycles,
+            counting: keep_counting,
+            publish: self.publish,
+            epoch: self.epoch,
+            cycle_offset: self.cycle_offset,
+            epochs: self.epochs,
+        };
+        (self.counter, self.timer, suspended)
+    }
 
-                TimerInstant::from_ticks(self.last)
-            }
+    /// Set the rate of the compare timer to the cycle counter clock divided
+    /// by `divisor`.
+    ///
+    /// The timer runs at the cycle counter clock by default and can not run
+    /// faster than it. The compare events are rounded up to the timer
+    /// resolution. For SysTick use
+    /// [`DwtSystick::with_systick_clock`] which also selects its clock source.
+    ///
+    /// The divisor must be supported by the timer (see
+    /// [`CompareTimer::max_divisor`]) and at most half the longest compare
+    /// interval (a quarter of the cycle counter period) so that interval
+    /// spans at least one timer tick.
+    ///
+    /// This must be done before any compare is set.
+    pub fn with_timer_divisor(mut self, divisor: u32) -> Result<Self, Error> {
+        check_divisor(divisor, self.timer.max_divisor(), C::BITS)?;
+        self.divisor = divisor;
+        Ok(self)
+    }
+
+    /// Select the origin of the instants.
+    ///
+    /// With [`Epoch::Boot`] (the default) `now()` counts from the start of
+    /// the cycle counter and `reset()` does not affect it. With
+    /// [`Epoch::Reset`] `now()` starts at zero when RTIC calls `reset()`, so
+    /// instants scheduled relative to `zero()` during `init` are relative to
+    /// the start of the application. Instants obtained before `reset()` are
+    /// then relative to the previous origin.
+    ///
+    /// The `Clock` handle and `unadjusted_now()` always count from the
+    /// start of the cycle counter and `adjusted_now()` always from
+    /// `reset()`.
+    pub fn with_epoch(mut self, epoch: Epoch) -> Self {
+        self.epoch = epoch;
+        self.publish_origin();
+        self
+    }
+
+    /// Mark the current time as the named epoch `name`, e.g. `"app"` at the
+    /// end of `init` or `"mode"` on every mode change.
+    ///
+    /// Marking an existing name moves it. Up to [`MAX_EPOCHS`] names are held,
+    /// returns [`Error::TooManyEpochs`] for more. Instants are converted to
+    /// offsets from an epoch by [`DwtSystick::since_epoch`].
+    pub fn mark_epoch(&mut self, name: &'static str) -> Result<(), Error> {
+        let cycles = self.cycles();
+        if self.epochs.mark(name, cycles) {
+            Ok(())
+        } else {
+            Err(Error::TooManyEpochs)
         }
     }
 
-    pub fn adjusted_now(&mut self) -> TimerInstant<TIMER_HZ> {
-        let unadjusted_now = self.unadjusted_now();
-        TimerInstant::from_ticks(unadjusted_now.ticks() - self.cycle_offset.ticks())
+    /// Remove the named epoch `name`, returning whether it was marked.
+    pub fn forget_epoch(&mut self, name: &str) -> bool {
+        self.epochs.forget(name)
     }
-}
 
-impl<const TIMER_HZ: u32> Monotonic for DwtSystick<TIMER_HZ> {
-    #[cfg(feature = "extend")]
-    const DISABLE_INTERRUPT_ON_EMPTY_QUEUE: bool = true;
-    #[cfg(not(feature = "extend"))]
-    // Need to detect and track overflows.
-    const DISABLE_INTERRUPT_ON_EMPTY_QUEUE: bool = false;
+    /// Call `hook` on any overflow tracking anomaly, see [`DwtSystick::health`].
+    ///
+    /// The hook runs in the context that detected the anomaly, typically the
+    /// SysTick interrupt.
+    pub fn with_health_hook(mut self, hook: fn(Anomaly)) -> Self {
+        self.monitor.set_hook(hook);
+        self
+    }
 
-    type Instant = TimerInstant<TIMER_HZ>;
-    type Duration = TimerDuration<TIMER_HZ>;
+    /// Overflow tracking health.
+    ///
+    /// With `u64` instants, time jumps back by `2**32` cycles if the cycle
+    /// counter is not observed for longer than half its period. Each
+    /// observation (`now()` and the SysTick interrupt) checks the gap to the
+    /// previous one, and each SysTick wrap checks that time advanced at least
+    /// by the programmed SysTick period.
+    ///
+    /// Without overflow tracking (`u32` instants from a 32 bit counter) the
+    /// instants wrap by design and nothing is checked.
+    pub fn health(&self) -> Health {
+        self.monitor.health()
+    }
 
-    #[inline(always)]
-    fn now(&mut self) -> Self::Instant {
-        self.unadjusted_now()
-    }
-
-    unsafe fn reset(&mut self) {
-        self.cycle_offset = self.unadjusted_now();
-    }
-
-    fn set_compare(&mut self, val: Self::Instant) {
-        // The input `val` refers to the cycle counter value (up-counter)
-        // but the SysTick is a down-counter with interrupt on zero.
-        let reload = val
-            .checked_duration_since(self.now())
-            // Minimum reload value if `val` is in the past
-            .map_or(0, |duration| duration.ticks())
-            // CYCCNT and SysTick have the same clock, no
-            // ticks conversion necessary, only clamping:
-            //
-            // ARM Architecture Reference Manual says:
-            // "Setting SYST_RVR to zero has the effect of
-            // disabling the SysTick counter independently
-            // of the counter enable bit.", so the min is 1
-            .max(1)
-            // SysTick is a 24 bit counter.
-            .min(0xff_ffff) as u32;
-
-        self.systick.set_reload(reload);
-        // Also clear the current counter. That doesn't cause a SysTick
-        // interrupt and loads the reload value on the next cycle.
-        self.systick.clear_current();
+    /// Call `hook` with the lateness in cycles whenever a compare event is
+    /// set for an instant already in the past, see
+    /// [`DwtSystick::deadline_misses`].
+    ///
+    /// The hook runs in the context calling `set_compare()`, i.e. the
+    /// scheduling task or the SysTick interrupt.
+    pub fn with_deadline_hook(mut self, hook: fn(u64)) -> Self {
+        self.deadlines.set_hook(hook);
+        self
     }
 
-    #[inline(always)]
-    fn zero() -> Self::Instant {
-        Self::Instant::from_ticks(0)
+    /// Missed deadlines: compare events set for instants already in the past.
+    ///
+    /// Those fire on the next SysTick clock instead. Scheduling a task at an
+    /// instant already passed thus counts as a miss.
+    pub fn deadline_misses(&self) -> DeadlineMisses {
+        self.deadlines.misses()
     }
 
-    #[inline(always)]
-    fn clear_compare_flag(&mut self) {
-        // SysTick exceptions don't need flag clearing.
-        //
-        // But when extending the cycle counter range, we need to keep
-        // the interrupts enabled to detect overflow.
-        // This function is always called in the interrupt handler early.
-        // Reset a maximum reload value in case `set_compare()` is not called.
-        // Otherwise the interrupt would keep firing at the previous set
-        // interval.
-        #[cfg(feature = "extend")]
-        {
-            self.systick.set_reload(0xff_ffff);
-            self.systick.clear_current();
+    /// Program compare events `cycles` early.
+    ///
+    /// This compensates the time from reading the cycle counter in
+    /// `set_compare()` to SysTick being loaded and the exception entry until
+    /// the interrupt handler calls `clear_compare_flag()`. The lead time can
+    /// be configured per chip and build or calibrated at startup, see
+    /// [`DwtSystick::calibrate_lead_time`].
+    pub fn with_lead_time(mut self, cycles: u32) -> Self {
+        self.wakeup.set_lead(cycles);
+        self
+    }
+
+    /// The lead time in cycles.
+    pub fn lead_time(&self) -> u32 {
+        self.wakeup.lead()
+    }
+
+    /// Cycles from the last compare instant to the interrupt handler calling
+    /// `clear_compare_flag()`, negative if early.
+    ///
+    /// `None` until a compare event within the SysTick range has fired.
+    pub fn wakeup_error(&self) -> Option<i64> {
+        self.wakeup.error()
+    }
+
+    /// Fire compare events `cycles` early and spin on the cycle counter to the
+    /// exact instant.
+    ///
+    /// The spinning is done in `clear_compare_flag()` at the beginning of the
+    /// SysTick interrupt handler, before RTIC dispatches the released tasks.
+    /// This gives cycle accurate release times at the expense of blocking the
+    /// SysTick priority for up to the margin (plus any latency variation).
+    /// The margin adds to the lead time. It should cover the jitter of the
+    /// interrupt latency, e.g. from higher priority interrupts and critical
+    /// sections.
+    ///
+    /// Each cycle counter read takes at least a cycle, so the spinning is
+    /// bounded to the lead time plus margin in reads. It also ends if the
+    /// counter does not advance, e.g. in simulation.
+    pub fn with_spin_margin(mut self, cycles: u32) -> Self {
+        self.wakeup.set_margin(cycles);
+        self
+    }
+
+    /// The spin margin in cycles.
+    pub fn spin_margin(&self) -> u32 {
+        self.wakeup.margin()
+    }
+
+    /// Add the last [`DwtSystick::wakeup_error`] to the lead time.
+    ///
+    /// Call this after a compare event, e.g. a few times at startup with an
+    /// otherwise idle system, to converge to the latency of the chip.
+    pub fn calibrate_lead_time(&mut self) {
+        self.wakeup.calibrate();
+    }
+
+    /// The extended cycle count, checking the overflow tracking.
+    fn cycles(&mut self) -> u64 {
+        let now = self.count();
+        // Without tracking the gaps are unbounded, e.g. while idle.
+        if Self::EXTEND {
+            self.monitor.observe(now);
+        }
+        now
+    }
+
+    /// The extended cycle count, publishing the overflow state.
+    fn count(&mut self) -> u64 {
+        let count = self.counter.cycle_count();
+        #[cfg(not(armv6m))]
+        if self.publish {
+            CYCCNT.update(count, C::BITS);
+        }
+        self.overflow.update(count, C::BITS)
+    }
+
+    /// Publish the origin of the instants for the [`Clock`] handles.
+    fn publish_origin(&self) {
+        #[cfg(not(armv6m))]
+        if self.publish {
+            ORIGIN.set(self.origin());
+        }
+    }
+
+    /// Extended cycle count at the origin of the instants.
+    fn origin(&self) -> u64 {
+        match self.epoch {
+            Epoch::Boot => 0,
+            Epoch::Reset => self.cycle_offset,
         }
     }
 
-    #[cfg(feature = "extend")]
-    fn on_interrupt(&mut self) {
-        // Ensure `now()` is called regularly to track overflows.
-        // Since SysTick is narrower than CYCCNT, this is sufficient.
-        self.now();
+    /// Cycles since the origin of the instants.
+    ///
+    /// Wraps like the instants: without overflow tracking the extended count
+    /// can fall behind the `reset()` offset.
+    fn epoch_cycles(&self, cycles: u64) -> u64 {
+        cycles.wrapping_sub(self.origin())
+    }
+
+    /// Longest compare interval in cycles.
+    fn range(&self) -> u64 {
+        let divisor = self.divisor as u64;
+        // One tick short as SysTick wraps a tick after counting down the
+        // reload value.
+        let ticks = (max_interval(C::BITS) / divisor - 1).min(self.timer.max_ticks());
+        ticks * divisor
+    }
+
+    /// Arm a compare event `cycles` from `now`, rounded up to the timer
+    /// resolution and limited to [`range()`](Self::range).
+    fn arm(&mut self, now: u64, cycles: u64) {
+        let divisor = self.divisor as u64;
+        let ticks = cycles
+            .min(self.range())
+            .div_ceil(divisor)
+            .max(self.min_ticks);
+        let now = now / divisor;
+        self.timer.arm_absolute(now + ticks, now);
+        self.monitor.arm(ticks * divisor);
     }
 }
+
+impl<const TIMER_HZ: u32, C: CycleCounter, T: DownCounter, W> DwtSystick<TIMER_HZ, C, T, W> {
+    /// Select the SysTick clock.
+    ///
+    /// SysTick runs from the core clock by default. Running it from a slower
+    /// clock allows longer compare intervals and thus fewer interrupts. The
+    /// compare events are rounded up to the SysTick resolution.
+    ///
+    /// This must be done before any compare is set.
+    pub fn with_systick_clock(mut self, clock: SystickClock) -> Result<Self, Error> {
+        self.set_systick_clock(clock)?;
+        Ok(self)
+    }
+
+    fn set_systick_clock(&mut self, clock: SystickClock) -> Result<(), Error> {
+        let (source, divisor) = clock.source()?;
+        check_divisor(divisor, self.timer.max_divisor(), C::BITS)?;
+        self.apply_systick_clock(source, divisor);
+        Ok(())
+    }
+
+    /// Switch to a checked SysTick clock.
+    fn apply_systick_clock(&mut self, source: SystClkSource, divisor: u32) {
+        self.timer.set_clock_source(source);
+        self.divisor = divisor;
+        // Re-arm the longest compare interval at the new rate.
+        let now = self.cycles();
+        self.arm(now, u64::MAX);
+    }
+}
+
+macro_rules! impl_dwt_systick {
+    ($ticks:ty) => {
+        impl<const TIMER_HZ: u32, C: CycleCounter, T: CompareTimer>
+            DwtSystick<TIMER_HZ, C, T, $ticks>
+        {
+            pub fn unadjusted_now(&mut self) -> fugit::TimerInstant<$ticks, TIMER_HZ> {
+                fugit::TimerInstant::<$ticks, TIMER_HZ>::from_ticks(self.cycles() as $ticks)
+            }
+
+            pub fn adjusted_now(&mut self) -> fugit::TimerInstant<$ticks, TIMER_HZ> {
+                let cycles = self.cycles().wrapping_sub(self.cycle_offset);
+                fugit::TimerInstant::<$ticks, TIMER_HZ>::from_ticks(cycles as $ticks)
+            }
+
+            /// The named epoch `name` as an instant of `now()`, `None` if it
+            /// is not marked.
+            ///
+            /// With [`Epoch::Reset`] an epoch marked before `reset()` is
+            /// before `zero()` and wraps.
+            pub fn epoch_instant(
+                &self,
+                name: &str,
+            ) -> Option<fugit::TimerInstant<$ticks, TIMER_HZ>> {
+                let cycles = self.epochs.get(name)?.wrapping_sub(self.origin());
+                Some(fugit::TimerInstant::<$ticks, TIMER_HZ>::from_ticks(
+                    cycles as $ticks,
+                ))
+            }
+
+            /// Time from the named epoch `name` to `instant`, an instant of
+            /// `now()`.
+            ///
+            /// `None` if `name` is not marked or `instant` is before it. The
+            /// instants wrap, so `instant` must be within half their range
+            /// of the epoch, i.e. `2**31` cycles for `u32` instants.
+            pub fn since_epoch(
+                &self,
+                name: &str,
+                instant: fugit::TimerInstant<$ticks, TIMER_HZ>,
+            ) -> Option<fugit::TimerDuration<$ticks, TIMER_HZ>> {
+                instant.checked_duration_since(self.epoch_instant(name)?)
+            }
+        }
+
+        impl<const TIMER_HZ: u32, C: CycleCounter, T: CompareTimer> Monotonic
+            for DwtSystick<TIMER_HZ, C, T, $ticks>
+        {
+            // Need to detect and track overflows when extending.
+            const DISABLE_INTERRUPT_ON_EMPTY_QUEUE: bool = !Self::EXTEND;
+
+            type Instant = fugit::TimerInstant<$ticks, TIMER_HZ>;
+            type Duration = fugit::TimerDuration<$ticks, TIMER_HZ>;
+
+            #[inline(always)]
+            fn now(&mut self) -> Self::Instant {
+                let cycles = self.cycles();
+                Self::Instant::from_ticks(self.epoch_cycles(cycles) as $ticks)
+            }
+
+            unsafe fn reset(&mut self) {
+                self.cycle_offset = self.cycles();
+                self.publish_origin();
+            }
+
+            fn set_compare(&mut self, val: Self::Instant) {
+                // The input `val` refers to the cycle counter value (up-counter),
+                // the compare timer counts its ticks at the divided rate.
+                let cycles = self.cycles();
+                let now = Self::Instant::from_ticks(self.epoch_cycles(cycles) as $ticks);
+                let ticks = match val.checked_duration_since(now) {
+                    Some(duration) => duration.ticks(),
+                    None => {
+                        // Instants exactly half the range ahead count as
+                        // past too.
+                        let lateness = now.ticks().wrapping_sub(val.ticks());
+                        self.deadlines.missed(lateness as u64);
+                        // Minimum reload value if `val` is in the past
+                        0
+                    }
+                };
+                let range = self.range();
+                let ticks = self.wakeup.arm(cycles, ticks as u64, range);
+                self.arm(cycles, ticks);
+            }
+
+            #[inline(always)]
+            fn zero() -> Self::Instant {
+                Self::Instant::from_ticks(0)
+            }
+
+            #[inline(always)]
+            fn clear_compare_flag(&mut self) {
+                // This function is always called in the interrupt handler early.
+                // Measure the wakeup if the armed interval has elapsed (and the
+                // interrupt was not just pended).
+                let wrapped = self.timer.clear();
+                let mut now = self.cycles();
+                if let Some(target) = self.wakeup.spin_target(wrapped) {
+                    for _ in 0..self.wakeup.spin_limit() {
+                        if now >= target {
+                            break;
+                        }
+                        now = self.cycles();
+                    }
+                }
+                self.wakeup.fired(wrapped, now);
+
+                // But when extending the cycle counter range, we need to keep
+                // the interrupts enabled to detect overflow.
+                // Re-arm the longest interval in case `set_compare()` is not called.
+                // Otherwise the interrupt would keep firing at the previous set
+                // interval.
+                if Self::EXTEND {
+                    // Cross-check the overflow tracking.
+                    if wrapped {
+                        self.monitor.wrapped();
+                    }
+
+                    match self.tracking {
+                        OverflowTracking::Interrupt => self.arm(now, u64::MAX),
+                        // Only interrupt again for the next `set_compare()`.
+                        OverflowTracking::Observed => self.timer.disarm(),
+                    }
+                }
+            }
+
+            fn on_interrupt(&mut self) {
+                // Ensure `now()` is called regularly to track overflows.
+                // The compare intervals are limited to a quarter of the
+                // cycle counter period, so this is sufficient.
+                self.now();
+            }
+
+            fn disable_timer(&mut self) {
+                self.timer.disarm();
+            }
+        }
+    };
+}
+
+impl_dwt_systick!(u32);
+impl_dwt_systick!(u64);
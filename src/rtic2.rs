@@ -0,0 +1,290 @@
+//! RTIC v2 [`Monotonic`] based on DWT and SysTick
+//!
+//! This uses the same scheme as [`DwtSystick`](crate::DwtSystick): time is
+//! read from the DWT cycle counter and SysTick is only used to obtain the
+//! compare events of the [`TimerQueue`].
+//!
+//! # Example
+//!
+//! ```no_run
+//! use dwt_systick_monotonic::{dwt_systick_monotonic, rtic2::Monotonic};
+//!
+//! // Create the type `Mono` running at the 72 MHz core clock.
+//! dwt_systick_monotonic!(Mono, 72_000_000);
+//!
+//! fn init() {
+//!     let mut cp = cortex_m::Peripherals::take().unwrap();
+//!     Mono::start(&mut cp.DCB, cp.DWT, cp.SYST, 72_000_000);
+//! }
+//!
+//! async fn usage() {
+//!     let deadline = Mono::now() + <Mono as Monotonic>::Duration::millis(100);
+//!     Mono::delay_until(deadline).await;
+//! }
+//! ```
+//!
+//! The width of the ticks is given by the optional third argument of the
+//! macro, `u32` or `u64` (e.g. `dwt_systick_monotonic!(Mono, 72_000_000, u64)`),
+//! and defaults to [`Ticks`](crate::Ticks). With `u64` the ticks are extended
+//! and SysTick keeps interrupting at least every `0xff_ffff` cycles to track
+//! cycle counter overflows.
+
+use cortex_m::peripheral::SCB;
+pub use cortex_m::peripheral::{DCB, DWT, SYST};
+#[cfg(feature = "embedded-hal-async")]
+pub use embedded_hal_async;
+use rtic_time::timer_queue::TimerQueue;
+pub use rtic_time::{
+    self, monotonic::TimerQueueBasedMonotonic, timer_queue::TimerQueueBackend, Monotonic,
+};
+
+/// [`TimerQueueBackend`] of the default width [`Ticks`](crate::Ticks).
+#[cfg(not(feature = "extend"))]
+pub type DwtSystickBackend = DwtSystickBackend32;
+/// [`TimerQueueBackend`] of the default width [`Ticks`](crate::Ticks).
+#[cfg(feature = "extend")]
+pub type DwtSystickBackend = DwtSystickBackend64;
+
+/// Start the DWT cycle counter and SysTick interrupting at the longest interval.
+fn start(dcb: &mut DCB, mut dwt: DWT, mut systick: SYST, sysclk: u32, timer_hz: u32) {
+    crate::check_frequency(timer_hz, sysclk).unwrap();
+    crate::enable_trace(dcb).unwrap();
+    crate::start(&mut dwt, &mut systick, Some(0)).unwrap();
+    crate::CYCCNT.set(0, crate::CYCCNT_BITS);
+
+    systick.set_reload(crate::SYST_MAX_RELOAD);
+    systick.clear_current();
+}
+
+fn systick() -> SYST {
+    // NOTE(unsafe) SysTick is owned by the backend after `_start()`.
+    unsafe { cortex_m::Peripherals::steal().SYST }
+}
+
+macro_rules! impl_backend {
+    ($backend:ident, $queue:ident, $ticks:ty, $signed:ty, $extend:literal) => {
+        static $queue: TimerQueue<$backend> = TimerQueue::new();
+
+        #[doc = concat!("DWT and SysTick based [`TimerQueueBackend`] with `", stringify!($ticks), "` ticks.")]
+        pub struct $backend;
+
+        impl $backend {
+            /// Starts the monotonic timer.
+            ///
+            /// **Do not use this function directly.**
+            ///
+            /// Use the [`dwt_systick_monotonic`](crate::dwt_systick_monotonic) macro instead.
+            pub fn _start(dcb: &mut DCB, dwt: DWT, systick: SYST, sysclk: u32, timer_hz: u32) {
+                start(dcb, dwt, systick, sysclk, timer_hz);
+                $queue.initialize($backend);
+            }
+
+            /// Wait for at least `value` units of `1 / per_second` seconds.
+            ///
+            /// **Do not use this function directly.**
+            ///
+            /// Use the `embedded_hal_async::delay::DelayNs` implementation of the
+            /// [`dwt_systick_monotonic`](crate::dwt_systick_monotonic) type instead.
+            #[cfg(feature = "embedded-hal-async")]
+            pub async fn _delay(value: u32, per_second: u64, timer_hz: u32) {
+                use rtic_time::timer_queue::TimerQueueTicks;
+
+                // Longest wait the timer queue can represent unambiguously.
+                const MAX_CHUNK: u64 = <$signed>::MAX as u64;
+
+                let mut cycles = crate::cycles_at_least(value, per_second, timer_hz);
+                let mut until = Self::now();
+                while cycles > 0 {
+                    let chunk = cycles.min(MAX_CHUNK);
+                    cycles -= chunk;
+                    until = until.wrapping_add(chunk as $ticks);
+                    // Below the minimum SysTick reload the compare event can't
+                    // be earlier than just spinning.
+                    if chunk > crate::SYST_MIN_RELOAD as u64 {
+                        $queue.delay_until(until).await;
+                    }
+                    while !Self::now().is_at_least(until) {}
+                }
+            }
+        }
+
+        impl TimerQueueBackend for $backend {
+            type Ticks = $ticks;
+
+            fn now() -> Self::Ticks {
+                if $extend {
+                    crate::CYCCNT.read(DWT::cycle_count, crate::CYCCNT_BITS) as $ticks
+                } else {
+                    DWT::cycle_count() as $ticks
+                }
+            }
+
+            fn set_compare(instant: Self::Ticks) {
+                // Ticks until `instant`, zero if it is in the past.
+                let ticks = instant.wrapping_sub(Self::now());
+                let ticks = if (ticks as $signed) < 0 { 0 } else { ticks };
+
+                let mut systick = systick();
+                systick.set_reload(crate::systick_reload(ticks, 1));
+                // Also clear the current counter. That doesn't cause a SysTick
+                // interrupt and loads the reload value on the next cycle.
+                systick.clear_current();
+            }
+
+            fn clear_compare_flag() {
+                // SysTick exceptions don't need flag clearing.
+                //
+                // Reset a maximum reload value in case `set_compare()` is not called.
+                // Otherwise the interrupt would keep firing at the previous set
+                // interval.
+                let mut systick = systick();
+                systick.set_reload(crate::SYST_MAX_RELOAD);
+                systick.clear_current();
+            }
+
+            fn pend_interrupt() {
+                SCB::set_pendst();
+            }
+
+            fn on_interrupt() {
+                // Track overflows from the interrupt only, `now()` may be called
+                // from any context.
+                // Since SysTick is narrower than CYCCNT, this is sufficient.
+                if $extend {
+                    crate::CYCCNT.update(DWT::cycle_count(), crate::CYCCNT_BITS);
+                }
+            }
+
+            fn enable_timer() {
+                if !$extend {
+                    systick().enable_interrupt();
+                }
+            }
+
+            fn disable_timer() {
+                // Only when extending the cycle counter range the interrupts
+                // need to keep firing to detect overflows.
+                if !$extend {
+                    systick().disable_interrupt();
+                }
+            }
+
+            fn timer_queue() -> &'static TimerQueue<Self> {
+                &$queue
+            }
+        }
+    };
+}
+
+impl_backend!(DwtSystickBackend32, TIMER_QUEUE_32, u32, i32, false);
+impl_backend!(DwtSystickBackend64, TIMER_QUEUE_64, u64, i64, true);
+
+/// Create a DWT and SysTick based monotonic and register the SysTick interrupt for it.
+///
+/// This macro expands to produce a new type called `$name`, which has a
+/// `fn start()` function for you to call. The type implements
+/// [`TimerQueueBasedMonotonic`] and thus [`Monotonic`] providing `now()`,
+/// `delay()`, `delay_until()`, `timeout_at()` and `timeout_after()`.
+///
+/// This macro also produces an interrupt handler for the SysTick interrupt, by
+/// creating an `extern "C" fn SysTick() { ... }`.
+///
+/// With the `embedded-hal-async` feature the type also implements
+/// `embedded_hal_async::delay::DelayNs`. Any number of tasks can wait
+/// concurrently, each registered in the timer queue.
+///
+/// # Arguments
+///
+/// * `name` - The name that the monotonic type will have.
+/// * `timer_hz` - The frequency of the DWT cycle counter and SysTick.
+/// * `width` - Optional width of the ticks, `u32` or `u64`. Defaults to
+///   [`Ticks`](crate::Ticks), prefer naming it as cargo features are unified.
+#[macro_export]
+macro_rules! dwt_systick_monotonic {
+    ($name:ident, $timer_hz:expr) => {
+        $crate::__dwt_systick_monotonic!($name, $timer_hz, $crate::rtic2::DwtSystickBackend);
+    };
+    ($name:ident, $timer_hz:expr, u32) => {
+        $crate::__dwt_systick_monotonic!($name, $timer_hz, $crate::rtic2::DwtSystickBackend32);
+    };
+    ($name:ident, $timer_hz:expr, u64) => {
+        $crate::__dwt_systick_monotonic!($name, $timer_hz, $crate::rtic2::DwtSystickBackend64);
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __dwt_systick_monotonic {
+    ($name:ident, $timer_hz:expr, $backend:ty) => {
+        /// A `Monotonic` based on DWT and SysTick.
+        pub struct $name;
+
+        impl $name {
+            /// Starts the `Monotonic`.
+            ///
+            /// Note that the `sysclk` parameter should come from e.g. the HAL's clock generation
+            /// function so the speed calculated at runtime and the declared speed can be compared.
+            ///
+            /// This method must be called only once.
+            pub fn start(
+                dcb: &mut $crate::rtic2::DCB,
+                dwt: $crate::rtic2::DWT,
+                systick: $crate::rtic2::SYST,
+                sysclk: u32,
+            ) {
+                #[no_mangle]
+                #[allow(non_snake_case)]
+                unsafe extern "C" fn SysTick() {
+                    use $crate::rtic2::TimerQueueBackend;
+                    <$backend>::timer_queue().on_monotonic_interrupt();
+                }
+
+                <$backend>::_start(dcb, dwt, systick, sysclk, $timer_hz);
+            }
+        }
+
+        impl $crate::rtic2::TimerQueueBasedMonotonic for $name {
+            type Backend = $backend;
+            type Instant = $crate::fugit::Instant<
+                <Self::Backend as $crate::rtic2::TimerQueueBackend>::Ticks,
+                1,
+                { $timer_hz },
+            >;
+            type Duration = $crate::fugit::Duration<
+                <Self::Backend as $crate::rtic2::TimerQueueBackend>::Ticks,
+                1,
+                { $timer_hz },
+            >;
+        }
+
+        $crate::__dwt_systick_async_delay!($name, $timer_hz, $backend);
+    };
+}
+
+#[cfg(feature = "embedded-hal-async")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __dwt_systick_async_delay {
+    ($name:ident, $timer_hz:expr, $backend:ty) => {
+        impl $crate::rtic2::embedded_hal_async::delay::DelayNs for $name {
+            async fn delay_ns(&mut self, ns: u32) {
+                <$backend>::_delay(ns, 1_000_000_000, $timer_hz).await;
+            }
+
+            async fn delay_us(&mut self, us: u32) {
+                <$backend>::_delay(us, 1_000_000, $timer_hz).await;
+            }
+
+            async fn delay_ms(&mut self, ms: u32) {
+                <$backend>::_delay(ms, 1_000, $timer_hz).await;
+            }
+        }
+    };
+}
+
+#[cfg(not(feature = "embedded-hal-async"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __dwt_systick_async_delay {
+    ($name:ident, $timer_hz:expr, $backend:ty) => {};
+}
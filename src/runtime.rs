@@ -0,0 +1,304 @@
+//! DWT and SysTick `Monotonic` with the core frequency known at runtime
+
+use core::marker::PhantomData;
+
+#[cfg(not(armv6m))]
+use cortex_m::peripheral::DCB;
+use cortex_m::peripheral::{DWT, SYST};
+use rtic_monotonic::Monotonic;
+
+use crate::{
+    hal::{CycleCounter, DownCounter},
+    max_interval, start, systick_reload, Error, Overflow, Rejected, Ticks, SYST_MAX_RELOAD,
+};
+
+/// DWT and Systick combination implementing `rtic_monotonic::Monotonic`
+/// with the core frequency configured at runtime.
+///
+/// This is like [`DwtSystick`](crate::DwtSystick) but the cycle counter
+/// frequency is a runtime value instead of the generic parameter. Instants
+/// and durations are in fixed units of `1 / UNIT_HZ` seconds, e.g.
+/// `UNIT_HZ = 1_000_000` for microseconds. They are obtained from the cycle
+/// count by exact rational conversion (rounded down for `now()` and up for
+/// compare events which thus never fire early).
+///
+/// The cycle counter is always extended to `u64` internally for the
+/// conversion, so the SysTick interrupt must not be disabled longer than a
+/// quarter of the cycle counter overflow period. The instants are of width `W`, `u32`
+/// or `u64` (default [`Ticks`]), and wrap at its maximum.
+///
+/// The core frequency can be changed while running, see
+/// [`RuntimeDwtSystick::set_sysclk`].
+pub struct RuntimeDwtSystick<const UNIT_HZ: u32, C = DWT, T = SYST, W = Ticks> {
+    counter: C,
+    systick: T,
+    sysclk: u32,
+    /// `UNIT_HZ / sysclk` in lowest terms.
+    num: u64,
+    den: u64,
+    overflow: Overflow,
+    /// Cycle count at the last frequency change.
+    base_cycles: u64,
+    /// Time at `base_cycles`: whole units and the remaining `1 / den` fraction.
+    base_units: u64,
+    base_frac: u64,
+    /// Pending compare event in units.
+    compare: Option<u64>,
+    offset: u64,
+    _width: PhantomData<W>,
+}
+
+#[cfg(not(armv6m))]
+impl<const UNIT_HZ: u32, W> RuntimeDwtSystick<UNIT_HZ, DWT, SYST, W> {
+    /// Enable the DWT and provide a new `Monotonic` based on DWT and SysTick
+    /// running at `sysclk`.
+    ///
+    /// # Panics
+    ///
+    /// On any configuration [`Error`], see [`RuntimeDwtSystick::try_new`].
+    #[inline(always)]
+    pub fn new(dcb: &mut DCB, dwt: DWT, systick: SYST, sysclk: u32) -> Self {
+        Self::try_new(dcb, dwt, systick, sysclk).unwrap()
+    }
+
+    /// Enable the DWT and provide a new `Monotonic` based on DWT and SysTick
+    /// running at `sysclk`.
+    ///
+    /// Like [`RuntimeDwtSystick::new`] but returns an [`Error`] together with the
+    /// peripherals if the configuration is invalid or the DWT can not be enabled.
+    pub fn try_new(
+        dcb: &mut DCB,
+        dwt: DWT,
+        systick: SYST,
+        sysclk: u32,
+    ) -> Result<Self, Rejected<DWT, SYST>> {
+        if let Err(error) = crate::enable_trace(dcb) {
+            return Err(Rejected {
+                error,
+                counter: dwt,
+                timer: systick,
+            });
+        }
+        Self::try_from_parts(dwt, systick, sysclk)
+    }
+}
+
+impl<const UNIT_HZ: u32, C: CycleCounter, T: DownCounter, W> RuntimeDwtSystick<UNIT_HZ, C, T, W> {
+    /// Rejects cycle counter widths the overflow extension can not handle
+    /// when the monotonic is instantiated.
+    const VALID_BITS: () = assert!(
+        C::BITS >= 3 && C::BITS <= 32,
+        "CycleCounter::BITS must be from 3 to 32"
+    );
+
+    /// Provide a new `Monotonic` from a cycle counter and a down-counter
+    /// running at `sysclk`.
+    ///
+    /// # Panics
+    ///
+    /// On any configuration [`Error`], see [`RuntimeDwtSystick::try_from_parts`].
+    #[inline(always)]
+    pub fn from_parts(counter: C, systick: T, sysclk: u32) -> Self {
+        Self::try_from_parts(counter, systick, sysclk).unwrap()
+    }
+
+    /// Provide a new `Monotonic` from a cycle counter and a down-counter
+    /// running at `sysclk`.
+    ///
+    /// Like [`RuntimeDwtSystick::from_parts`] but returns an [`Error`] together
+    /// with the peripherals if the configuration is invalid or the counter can
+    /// not be enabled.
+    /// Any global enable the counter depends on (like `DCB` trace enable)
+    /// must be set up by the caller.
+    pub fn try_from_parts(
+        mut counter: C,
+        mut systick: T,
+        sysclk: u32,
+    ) -> Result<Self, Rejected<C, T>> {
+        let () = Self::VALID_BITS;
+        let started = if sysclk == 0 || UNIT_HZ == 0 {
+            Err(Error::ZeroFrequency)
+        } else {
+            start(&mut counter, &mut systick, Some(0))
+        };
+        if let Err(error) = started {
+            return Err(Rejected {
+                error,
+                counter,
+                timer: systick,
+            });
+        }
+
+        let mut mono = Self {
+            counter,
+            systick,
+            sysclk: 0,
+            num: 1,
+            den: 1,
+            overflow: Overflow::new(),
+            base_cycles: 0,
+            base_units: 0,
+            base_frac: 0,
+            compare: None,
+            offset: 0,
+            _width: PhantomData,
+        };
+        mono.set_ratio(sysclk);
+        Ok(mono)
+    }
+
+    /// The cycle counter frequency.
+    pub fn sysclk(&self) -> u32 {
+        self.sysclk
+    }
+
+    /// Change the cycle counter frequency to `sysclk`.
+    ///
+    /// Call this right after switching the core clock, preferably in the same
+    /// critical section. The time elapsed at the old frequency is carried
+    /// over so `now()` stays continuous and monotonic; cycles counted between
+    /// the clock switch and this call are attributed to the new frequency.
+    /// A pending compare event is re-programmed for the new SysTick rate.
+    ///
+    /// Returns [`Error::ZeroFrequency`] and leaves the frequency unchanged if
+    /// `sysclk` is zero.
+    pub fn set_sysclk(&mut self, sysclk: u32) -> Result<(), Error> {
+        if sysclk == 0 {
+            return Err(Error::ZeroFrequency);
+        }
+
+        let cycles = self.cycles();
+        let (units, frac) = self.elapsed(cycles);
+        let den = self.den;
+        self.set_ratio(sysclk);
+        self.base_cycles = cycles;
+        self.base_units = units;
+        // Rescale the sub-unit fraction, losing less than one cycle.
+        self.base_frac = (frac as u128 * self.den as u128 / den as u128) as u64;
+
+        if let Some(at) = self.compare {
+            let units = at.saturating_sub(self.units());
+            self.compare_in(units);
+        }
+        Ok(())
+    }
+
+    fn set_ratio(&mut self, sysclk: u32) {
+        let gcd = gcd(UNIT_HZ, sysclk) as u64;
+        self.sysclk = sysclk;
+        self.num = UNIT_HZ as u64 / gcd;
+        self.den = sysclk as u64 / gcd;
+    }
+
+    /// The extended cycle count.
+    fn cycles(&mut self) -> u64 {
+        self.overflow.update(self.counter.cycle_count(), C::BITS)
+    }
+
+    /// Time at `cycles` in whole units and the remaining `1 / den` fraction.
+    fn elapsed(&self, cycles: u64) -> (u64, u64) {
+        // Can't overflow: 2**64 * 2**32 < 2**128
+        let frac = (cycles - self.base_cycles) as u128 * self.num as u128 + self.base_frac as u128;
+        let den = self.den as u128;
+        (self.base_units + (frac / den) as u64, (frac % den) as u64)
+    }
+
+    /// Convert units to cycles, rounding up.
+    fn to_cycles(&self, units: u64) -> u64 {
+        (units as u128 * self.den as u128).div_ceil(self.num as u128) as u64
+    }
+
+    /// Units since start.
+    fn units(&mut self) -> u64 {
+        let cycles = self.cycles();
+        self.elapsed(cycles).0
+    }
+
+    /// Program a compare event `units` from now.
+    fn compare_in(&mut self, units: u64) {
+        self.compare = Some(self.units() + units);
+        let cycles = self.to_cycles(units).min(max_interval(C::BITS));
+        let reload = systick_reload(cycles, 1);
+
+        self.systick.set_reload(reload);
+        // Also clear the current counter. That doesn't cause a SysTick
+        // interrupt and loads the reload value on the next cycle.
+        self.systick.clear_current();
+    }
+}
+
+macro_rules! impl_runtime_dwt_systick {
+    ($ticks:ty) => {
+        impl<const UNIT_HZ: u32, C: CycleCounter, T: DownCounter>
+            RuntimeDwtSystick<UNIT_HZ, C, T, $ticks>
+        {
+            pub fn unadjusted_now(&mut self) -> fugit::TimerInstant<$ticks, UNIT_HZ> {
+                fugit::TimerInstant::<$ticks, UNIT_HZ>::from_ticks(self.units() as $ticks)
+            }
+
+            pub fn adjusted_now(&mut self) -> fugit::TimerInstant<$ticks, UNIT_HZ> {
+                let unadjusted_now = self.unadjusted_now();
+                fugit::TimerInstant::<$ticks, UNIT_HZ>::from_ticks(
+                    unadjusted_now.ticks().wrapping_sub(self.offset as $ticks),
+                )
+            }
+        }
+
+        impl<const UNIT_HZ: u32, C: CycleCounter, T: DownCounter> Monotonic
+            for RuntimeDwtSystick<UNIT_HZ, C, T, $ticks>
+        {
+            // Need to detect and track overflows.
+            const DISABLE_INTERRUPT_ON_EMPTY_QUEUE: bool = false;
+
+            type Instant = fugit::TimerInstant<$ticks, UNIT_HZ>;
+            type Duration = fugit::TimerDuration<$ticks, UNIT_HZ>;
+
+            #[inline(always)]
+            fn now(&mut self) -> Self::Instant {
+                self.unadjusted_now()
+            }
+
+            unsafe fn reset(&mut self) {
+                self.offset = self.unadjusted_now().ticks() as u64;
+            }
+
+            fn set_compare(&mut self, val: Self::Instant) {
+                let units = val
+                    .checked_duration_since(self.now())
+                    // Minimum reload value if `val` is in the past
+                    .map_or(0, |duration| duration.ticks());
+                self.compare_in(units as u64);
+            }
+
+            #[inline(always)]
+            fn zero() -> Self::Instant {
+                Self::Instant::from_ticks(0)
+            }
+
+            #[inline(always)]
+            fn clear_compare_flag(&mut self) {
+                self.compare = None;
+                // Keep the interrupts firing to detect overflow.
+                // Reset a maximum reload value in case `set_compare()` is not called.
+                self.systick.set_reload(SYST_MAX_RELOAD);
+                self.systick.clear_current();
+            }
+
+            fn on_interrupt(&mut self) {
+                // Ensure `now()` is called regularly to track overflows.
+                self.now();
+            }
+        }
+    };
+}
+
+impl_runtime_dwt_systick!(u32);
+impl_runtime_dwt_systick!(u64);
+
+/// Greatest common divisor.
+fn gcd(mut a: u32, mut b: u32) -> u32 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
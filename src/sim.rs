@@ -0,0 +1,367 @@
+//! Deterministic software model of the DWT cycle counter and SysTick
+//!
+//! The [`Simulator`] holds the state of both counters and hands out
+//! [`SimDwt`] and [`SimSyst`] implementing the [`hal`](crate::hal) traits so
+//! they can replace the peripherals in [`DwtSystick`](crate::DwtSystick).
+//! Time only advances when the simulator is stepped or advanced. Both
+//! counters are clocked by the same simulated core clock unless SysTick
+//! selects the external reference clock which runs at the core clock divided
+//! by [`Simulator::set_external_divisor`].
+//!
+//! The SysTick model follows the ARMv7-M Architecture Reference Manual: the
+//! counter decrements on each clock, sets `COUNTFLAG` and pends its exception
+//! (if `TICKINT` is set) when it transitions from 1 to 0 and loads the reload
+//! value on the clock after it reached 0. A reload value of zero stops it at
+//! 0. Writing the current value clears it to 0 and clears `COUNTFLAG`
+//! without pending the exception. Exception masking and priorities are left
+//! to the harness.
+//!
+//! To drive a monotonic the way RTIC does, advance the simulator to the next
+//! pending exception and then call `clear_compare_flag()`, `on_interrupt()`
+//! and `set_compare()` like the RTIC SysTick handler would:
+//!
+//! ```
+//! use dwt_systick_monotonic::{fugit::ExtU32, sim::Simulator, DwtSystick};
+//! use rtic_monotonic::Monotonic;
+//!
+//! let sim = Simulator::new();
+//! let mut mono =
+//!     DwtSystick::<1_000_000, _, _>::from_parts(sim.dwt(), sim.systick(), 1_000_000);
+//! let deadline = mono.now() + 1000.micros();
+//! mono.set_compare(deadline);
+//!
+//! let elapsed = sim.advance_to_exception(u64::MAX).unwrap();
+//! assert!(sim.take_pending());
+//! mono.clear_compare_flag();
+//! mono.on_interrupt();
+//! assert!(elapsed >= 1000);
+//! assert!(mono.now() >= deadline);
+//! ```
+
+use core::cell::Cell;
+
+use cortex_m::peripheral::syst::SystClkSource;
+
+use crate::hal::{CycleCounter, DownCounter};
+
+/// SysTick is a 24 bit counter.
+const SYST_MASK: u32 = 0xff_ffff;
+
+/// Simulated DWT cycle counter and SysTick sharing one core clock.
+#[derive(Debug, Default)]
+pub struct Simulator {
+    cycles: Cell<u64>,
+
+    cyccnt: Cell<u32>,
+    cyccnt_enabled: Cell<bool>,
+    dwt_locked: Cell<bool>,
+    dwt_lock_releasable: Cell<bool>,
+    no_cycle_counter: Cell<bool>,
+
+    syst_enabled: Cell<bool>,
+    syst_clock_source: Cell<Option<SystClkSource>>,
+    syst_external_divisor: Cell<u32>,
+    /// Core clock cycles into the current external reference clock period.
+    syst_prescaler: Cell<u64>,
+    syst_reload: Cell<u32>,
+    syst_current: Cell<u32>,
+    syst_countflag: Cell<bool>,
+    syst_tickint: Cell<bool>,
+    syst_pending: Cell<bool>,
+    syst_wraps: Cell<u64>,
+}
+
+impl Simulator {
+    /// A simulator with both counters disabled and zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The simulated DWT cycle counter.
+    pub fn dwt(&self) -> SimDwt<'_> {
+        SimDwt { sim: self }
+    }
+
+    /// The simulated SysTick.
+    pub fn systick(&self) -> SimSyst<'_> {
+        SimSyst { sim: self }
+    }
+
+    /// Core clock cycles elapsed since the simulator was created.
+    pub fn cycles(&self) -> u64 {
+        self.cycles.get()
+    }
+
+    /// The current `CYCCNT` value.
+    pub fn cyccnt(&self) -> u32 {
+        self.cyccnt.get()
+    }
+
+    /// Whether the cycle counter was enabled.
+    pub fn cyccnt_enabled(&self) -> bool {
+        self.cyccnt_enabled.get()
+    }
+
+    /// Software lock the DWT like some devices do after a power cycle.
+    ///
+    /// Writes to the cycle counter are ignored until it is unlocked. If the
+    /// lock is not `releasable`, unlocking has no effect.
+    pub fn lock_dwt(&self, releasable: bool) {
+        self.dwt_locked.set(true);
+        self.dwt_lock_releasable.set(releasable);
+    }
+
+    /// Model a DWT without cycle counter like on ARMv6-M.
+    pub fn remove_cycle_counter(&self) {
+        self.no_cycle_counter.set(true);
+    }
+
+    /// Set `CYCCNT`, e.g. to start close to an overflow.
+    pub fn set_cyccnt(&self, count: u32) {
+        self.cyccnt.set(count);
+    }
+
+    /// The current SysTick reload value (`SYST_RVR`).
+    pub fn syst_reload(&self) -> u32 {
+        self.syst_reload.get()
+    }
+
+    /// The current SysTick value (`SYST_CVR`).
+    pub fn syst_current(&self) -> u32 {
+        self.syst_current.get()
+    }
+
+    /// Whether SysTick was enabled.
+    pub fn syst_enabled(&self) -> bool {
+        self.syst_enabled.get()
+    }
+
+    /// The selected SysTick clock source, `None` if never configured.
+    pub fn syst_clock_source(&self) -> Option<SystClkSource> {
+        self.syst_clock_source.get()
+    }
+
+    /// Set the ratio of the core clock to the SysTick external reference clock.
+    pub fn set_external_divisor(&self, divisor: u32) {
+        self.syst_external_divisor.set(divisor);
+    }
+
+    /// Core clock cycles per SysTick clock.
+    fn syst_divisor(&self) -> u64 {
+        match self.syst_clock_source.get() {
+            Some(SystClkSource::External) => self.syst_external_divisor.get().max(1) as u64,
+            _ => 1,
+        }
+    }
+
+    /// Read and clear `COUNTFLAG` like reading `SYST_CSR` does.
+    pub fn syst_has_wrapped(&self) -> bool {
+        self.syst_countflag.replace(false)
+    }
+
+    /// Number of SysTick transitions to zero since the simulator was created.
+    pub fn syst_wraps(&self) -> u64 {
+        self.syst_wraps.get()
+    }
+
+    /// Whether SysTick raises its exception when reaching zero (`TICKINT`).
+    pub fn syst_interrupt_enabled(&self) -> bool {
+        self.syst_tickint.get()
+    }
+
+    /// Whether the SysTick exception is pending.
+    pub fn pending(&self) -> bool {
+        self.syst_pending.get()
+    }
+
+    /// Acknowledge a pending SysTick exception, i.e. enter its handler.
+    ///
+    /// Returns whether the exception was pending.
+    pub fn take_pending(&self) -> bool {
+        self.syst_pending.replace(false)
+    }
+
+    /// Pend the SysTick exception, like writing `ICSR.PENDSTSET`.
+    pub fn set_pending(&self) {
+        self.syst_pending.set(true);
+    }
+
+    /// Advance by a single core clock cycle.
+    pub fn step(&self) {
+        self.advance(1);
+    }
+
+    /// Advance by `cycles` core clock cycles.
+    pub fn advance(&self, cycles: u64) {
+        self.cycles.set(self.cycles.get().wrapping_add(cycles));
+        if self.cyccnt_enabled.get() {
+            self.cyccnt
+                .set(self.cyccnt.get().wrapping_add(cycles as u32));
+        }
+        if self.syst_enabled.get() {
+            let divisor = self.syst_divisor();
+            let phase = self.syst_prescaler.get() + cycles;
+            self.syst_prescaler.set(phase % divisor);
+            self.advance_systick(phase / divisor);
+        }
+    }
+
+    /// Advance until the SysTick exception becomes pending, at most `limit`
+    /// cycles.
+    ///
+    /// Returns the number of cycles advanced if the exception is pending
+    /// and `None` if the limit was reached without it. An exception that is
+    /// already pending returns `Some(0)`.
+    pub fn advance_to_exception(&self, limit: u64) -> Option<u64> {
+        if self.syst_pending.get() {
+            return Some(0);
+        }
+        let cycles = self.cycles_to_exception().filter(|&c| c <= limit);
+        self.advance(cycles.unwrap_or(limit));
+        cycles
+    }
+
+    /// Cycles until SysTick next pends its exception, `None` if it is stopped
+    /// or its interrupt is disabled.
+    fn cycles_to_exception(&self) -> Option<u64> {
+        if !self.syst_enabled.get() || !self.syst_tickint.get() {
+            return None;
+        }
+        let ticks = match (self.syst_current.get(), self.syst_reload.get()) {
+            (0, 0) => return None,
+            (0, reload) => reload as u64 + 1,
+            (current, _) => current as u64,
+        };
+        Some(ticks * self.syst_divisor() - self.syst_prescaler.get())
+    }
+
+    /// Advance SysTick by `ticks` SysTick clocks.
+    fn advance_systick(&self, mut ticks: u64) {
+        let reload = self.syst_reload.get() as u64;
+        let mut current = self.syst_current.get() as u64;
+        while ticks > 0 {
+            if current == 0 {
+                if reload == 0 {
+                    break;
+                }
+                // Full periods of `reload + 1` ticks each end with a wrap.
+                let periods = (ticks - 1) / (reload + 1);
+                if periods > 0 {
+                    self.wrap(periods);
+                    ticks -= periods * (reload + 1);
+                }
+                current = reload;
+                ticks -= 1;
+            } else {
+                let step = ticks.min(current);
+                current -= step;
+                ticks -= step;
+                if current == 0 {
+                    self.wrap(1);
+                }
+            }
+        }
+        self.syst_current.set(current as u32);
+    }
+
+    fn wrap(&self, count: u64) {
+        self.syst_countflag.set(true);
+        if self.syst_tickint.get() {
+            self.syst_pending.set(true);
+        }
+        self.syst_wraps.set(self.syst_wraps.get() + count);
+    }
+}
+
+/// Simulated DWT cycle counter, see [`Simulator::dwt`].
+#[derive(Clone, Copy, Debug)]
+pub struct SimDwt<'a> {
+    sim: &'a Simulator,
+}
+
+impl CycleCounter for SimDwt<'_> {
+    fn unlock(&mut self) {
+        if self.sim.dwt_lock_releasable.get() {
+            self.sim.dwt_locked.set(false);
+        }
+    }
+
+    fn is_locked(&self) -> bool {
+        self.sim.dwt_locked.get()
+    }
+
+    fn has_cycle_counter(&self) -> bool {
+        !self.sim.no_cycle_counter.get()
+    }
+
+    fn set_cycle_count(&mut self, count: u32) {
+        if !self.sim.dwt_locked.get() {
+            self.sim.cyccnt.set(count);
+        }
+    }
+
+    fn enable_cycle_counter(&mut self) {
+        if !self.sim.dwt_locked.get() {
+            self.sim.cyccnt_enabled.set(true);
+        }
+    }
+
+    fn disable_cycle_counter(&mut self) {
+        if !self.sim.dwt_locked.get() {
+            self.sim.cyccnt_enabled.set(false);
+        }
+    }
+
+    fn cycle_count(&self) -> u32 {
+        self.sim.cyccnt.get()
+    }
+}
+
+/// Simulated SysTick, see [`Simulator::systick`].
+#[derive(Clone, Copy, Debug)]
+pub struct SimSyst<'a> {
+    sim: &'a Simulator,
+}
+
+impl DownCounter for SimSyst<'_> {
+    fn set_clock_source(&mut self, clk_source: SystClkSource) {
+        self.sim.syst_clock_source.set(Some(clk_source));
+    }
+
+    fn enable_counter(&mut self) {
+        self.sim.syst_enabled.set(true);
+    }
+
+    fn disable_counter(&mut self) {
+        self.sim.syst_enabled.set(false);
+    }
+
+    fn set_reload(&mut self, value: u32) {
+        self.sim.syst_reload.set(value & SYST_MASK);
+    }
+
+    fn clear_current(&mut self) {
+        self.sim.syst_current.set(0);
+        self.sim.syst_countflag.set(false);
+    }
+
+    fn current(&self) -> u32 {
+        self.sim.syst_current()
+    }
+
+    fn has_wrapped(&mut self) -> bool {
+        self.sim.syst_has_wrapped()
+    }
+
+    fn enable_interrupt(&mut self) {
+        self.sim.syst_tickint.set(true);
+    }
+
+    fn disable_interrupt(&mut self) {
+        self.sim.syst_tickint.set(false);
+    }
+
+    fn clear_pending(&mut self) {
+        self.sim.syst_pending.set(false);
+    }
+}
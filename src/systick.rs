@@ -0,0 +1,162 @@
+//! SysTick-only `Monotonic` for cores without DWT cycle counter
+
+use core::marker::PhantomData;
+
+use cortex_m::peripheral::{syst::SystClkSource, SYST};
+use rtic_monotonic::Monotonic;
+
+use crate::{check_frequency, hal::DownCounter, systick_reload, Error, Ticks, SYST_MAX_RELOAD};
+
+/// SysTick implementing `rtic_monotonic::Monotonic` without DWT.
+///
+/// This is a fallback for cores without a DWT cycle counter (ARMv6-M). Time is
+/// obtained by accumulating the SysTick periods in software and combining
+/// them with the current SysTick value. SysTick also provides the compare
+/// events like in [`DwtSystick`](crate::DwtSystick). The instants are of width
+/// `W`, `u32` or `u64` (default [`Ticks`]), and wrap at its maximum.
+///
+/// Each period is accumulated in the SysTick interrupt. The interrupt must not
+/// be disabled for longer than the SysTick period of `0x100_0000` cycles.
+///
+/// Re-programming SysTick for a compare event loses the few cycles between
+/// reading and clearing the current value. Time thus lags the core clock by
+/// a couple cycles per compare event.
+pub struct SystickOnly<const TIMER_HZ: u32, T = SYST, W = Ticks> {
+    systick: T,
+    /// Time when SysTick was last loaded from `reload`.
+    base: u64,
+    reload: u32,
+    cycle_offset: u64,
+    _width: PhantomData<W>,
+}
+
+impl<const TIMER_HZ: u32, T: DownCounter, W> SystickOnly<TIMER_HZ, T, W> {
+    /// Start SysTick and provide a new `Monotonic` based on it.
+    ///
+    /// Note that the `sysclk` parameter should come from e.g. the HAL's clock generation function
+    /// so the speed calculated at runtime and the declared speed (generic parameter
+    /// `TIMER_HZ`) can be compared.
+    ///
+    /// # Panics
+    ///
+    /// On any configuration [`Error`], see [`SystickOnly::try_new`].
+    #[inline(always)]
+    pub fn new(systick: T, sysclk: u32) -> Self {
+        Self::try_new(systick, sysclk).unwrap()
+    }
+
+    /// Start SysTick and provide a new `Monotonic` based on it.
+    ///
+    /// Like [`SystickOnly::new`] but returns an [`Error`] if the configuration is invalid.
+    pub fn try_new(mut systick: T, sysclk: u32) -> Result<Self, Error> {
+        check_frequency(TIMER_HZ, sysclk)?;
+
+        systick.set_clock_source(SystClkSource::Core);
+        systick.set_reload(SYST_MAX_RELOAD);
+        systick.clear_current();
+        systick.enable_counter();
+
+        Ok(Self {
+            systick,
+            // SysTick loads the reload value on the next cycle.
+            base: 1,
+            reload: SYST_MAX_RELOAD,
+            cycle_offset: 0,
+            _width: PhantomData,
+        })
+    }
+
+    /// Cycles since SysTick was started.
+    fn cycles(&mut self) -> u64 {
+        let mut current = self.systick.current();
+        if self.systick.has_wrapped() {
+            self.base += self.reload as u64 + 1;
+            // Re-read as the wrap may have happened after the first read.
+            current = self.systick.current();
+        }
+        if current == 0 {
+            // Reached zero (or cleared), about to load `reload`.
+            self.base - 1
+        } else {
+            self.base + (self.reload - current) as u64
+        }
+    }
+
+    /// Load a new reload value, accounting for the elapsed part of the current period.
+    fn reprogram(&mut self, reload: u32) {
+        let now = self.cycles();
+        self.systick.set_reload(reload);
+        // Also clear the current counter. That doesn't cause a SysTick
+        // interrupt and loads the reload value on the next cycle.
+        self.systick.clear_current();
+        self.base = now + 1;
+        self.reload = reload;
+    }
+}
+
+macro_rules! impl_systick_only {
+    ($ticks:ty) => {
+        impl<const TIMER_HZ: u32, T: DownCounter> SystickOnly<TIMER_HZ, T, $ticks> {
+            pub fn unadjusted_now(&mut self) -> fugit::TimerInstant<$ticks, TIMER_HZ> {
+                fugit::TimerInstant::<$ticks, TIMER_HZ>::from_ticks(self.cycles() as $ticks)
+            }
+
+            pub fn adjusted_now(&mut self) -> fugit::TimerInstant<$ticks, TIMER_HZ> {
+                let unadjusted_now = self.unadjusted_now();
+                fugit::TimerInstant::<$ticks, TIMER_HZ>::from_ticks(
+                    unadjusted_now
+                        .ticks()
+                        .wrapping_sub(self.cycle_offset as $ticks),
+                )
+            }
+        }
+
+        impl<const TIMER_HZ: u32, T: DownCounter> Monotonic for SystickOnly<TIMER_HZ, T, $ticks> {
+            // Need to accumulate the SysTick periods.
+            const DISABLE_INTERRUPT_ON_EMPTY_QUEUE: bool = false;
+
+            type Instant = fugit::TimerInstant<$ticks, TIMER_HZ>;
+            type Duration = fugit::TimerDuration<$ticks, TIMER_HZ>;
+
+            #[inline(always)]
+            fn now(&mut self) -> Self::Instant {
+                self.unadjusted_now()
+            }
+
+            unsafe fn reset(&mut self) {
+                self.cycle_offset = self.unadjusted_now().ticks() as u64;
+            }
+
+            fn set_compare(&mut self, val: Self::Instant) {
+                let reload = systick_reload(
+                    val.checked_duration_since(self.now())
+                        // Minimum reload value if `val` is in the past
+                        .map_or(0, |duration| duration.ticks()),
+                    1,
+                );
+                self.reprogram(reload);
+            }
+
+            #[inline(always)]
+            fn zero() -> Self::Instant {
+                Self::Instant::from_ticks(0)
+            }
+
+            #[inline(always)]
+            fn clear_compare_flag(&mut self) {
+                // Reset a maximum reload value in case `set_compare()` is not called.
+                // Otherwise the interrupt would keep firing at the previous set
+                // interval.
+                self.reprogram(SYST_MAX_RELOAD);
+            }
+
+            fn on_interrupt(&mut self) {
+                // Accumulate the elapsed period.
+                self.now();
+            }
+        }
+    };
+}
+
+impl_systick_only!(u32);
+impl_systick_only!(u64);
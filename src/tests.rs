@@ -0,0 +1,537 @@
+//! Scenarios driving the monotonics with the simulated DWT and SysTick
+
+use fugit::{ExtU32, ExtU64};
+use rtic_monotonic::Monotonic;
+
+use crate::{
+    hal::CycleCounter,
+    max_interval,
+    sim::{SimDwt, SimSyst, Simulator},
+    Anomaly, Builder, DeadlineMisses, DwtSystick, DwtSystick32, DwtSystick64, Epoch, Error, Origin,
+    Overflow, OverflowTracking, RuntimeDwtSystick, SystickClock,
+};
+
+const HZ: u32 = 1_000_000;
+
+type Mono32<'a> = DwtSystick32<HZ, SimDwt<'a>, SimSyst<'a>>;
+type Mono64<'a> = DwtSystick64<HZ, SimDwt<'a>, SimSyst<'a>>;
+
+/// The simulated cycle counter truncated to `BITS`.
+struct Narrow<'a, const BITS: u32>(SimDwt<'a>);
+
+impl<const BITS: u32> CycleCounter for Narrow<'_, BITS> {
+    const BITS: u32 = BITS;
+
+    fn set_cycle_count(&mut self, count: u32) {
+        self.0.set_cycle_count(count);
+    }
+
+    fn enable_cycle_counter(&mut self) {
+        self.0.enable_cycle_counter();
+    }
+
+    fn disable_cycle_counter(&mut self) {
+        self.0.disable_cycle_counter();
+    }
+
+    fn cycle_count(&self) -> u32 {
+        self.0.cycle_count() & (u32::MAX >> (32 - BITS))
+    }
+}
+
+/// The simulated cycle counter taking a cycle per read like the DWT does.
+struct Ticking<'a>(&'a Simulator);
+
+impl CycleCounter for Ticking<'_> {
+    fn set_cycle_count(&mut self, count: u32) {
+        self.0.dwt().set_cycle_count(count);
+    }
+
+    fn enable_cycle_counter(&mut self) {
+        self.0.dwt().enable_cycle_counter();
+    }
+
+    fn disable_cycle_counter(&mut self) {
+        self.0.dwt().disable_cycle_counter();
+    }
+
+    fn cycle_count(&self) -> u32 {
+        self.0.advance(1);
+        self.0.cyccnt()
+    }
+}
+
+/// Advance to the pending SysTick exception (at most `limit` cycles) and run
+/// the handler like RTIC does. Returns the cycles advanced.
+fn interrupt<M: Monotonic>(sim: &Simulator, mono: &mut M, limit: u64) -> Option<u64> {
+    let elapsed = sim.advance_to_exception(limit)?;
+    assert!(sim.take_pending());
+    mono.clear_compare_flag();
+    mono.on_interrupt();
+    Some(elapsed)
+}
+
+#[test]
+fn compare_fires_at_instant() {
+    let sim = Simulator::new();
+    let mut mono = Mono64::from_parts(sim.dwt(), sim.systick(), HZ);
+    let instant = mono.now() + 1000u64.micros();
+    mono.set_compare(instant);
+
+    assert_eq!(interrupt(&sim, &mut mono, u64::MAX), Some(1001));
+    assert!(mono.now() >= instant);
+    assert_eq!(mono.deadline_misses(), DeadlineMisses::default());
+    assert!(mono.health().is_ok());
+}
+
+#[test]
+fn compare_across_u32_wrap() {
+    let sim = Simulator::new();
+    let mut mono = Mono32::from_parts(sim.dwt(), sim.systick(), HZ);
+    sim.set_cyccnt(u32::MAX - 100);
+    let instant = mono.now() + 1000u32.micros();
+    assert_eq!(instant.ticks(), 899);
+    mono.set_compare(instant);
+
+    interrupt(&sim, &mut mono, u64::MAX).unwrap();
+    assert!(mono.now() >= instant);
+    assert_eq!(mono.deadline_misses().count, 0);
+}
+
+#[test]
+fn late_compare_fires_at_once() {
+    let sim = Simulator::new();
+    let mut mono = Mono64::from_parts(sim.dwt(), sim.systick(), HZ);
+    let instant = mono.now() + 100u64.micros();
+    sim.advance(600);
+    mono.set_compare(instant);
+
+    let misses = mono.deadline_misses();
+    assert_eq!(misses.count, 1);
+    assert_eq!(misses.last, 500);
+    // The minimum reload value: on the next SysTick clock.
+    assert!(interrupt(&sim, &mut mono, u64::MAX).unwrap() <= 2);
+}
+
+#[test]
+fn late_compare_half_range_ahead_u32() {
+    let sim = Simulator::new();
+    let mut mono = Mono32::from_parts(sim.dwt(), sim.systick(), HZ);
+    let instant = mono.now() + (1u32 << 31).micros();
+    mono.set_compare(instant);
+
+    assert_eq!(mono.deadline_misses().last, 1 << 31);
+}
+
+#[test]
+fn idle_monotonic_is_reentered_by_interrupts() {
+    let sim = Simulator::new();
+    let mut mono = Mono64::from_parts(sim.dwt(), sim.systick(), HZ);
+    let instant = mono.now() + 10_000_000_000u64.micros();
+    mono.set_compare(instant);
+
+    // Re-armed at least every quarter of the cycle counter period.
+    let mut elapsed = 0;
+    while mono.now() < instant {
+        let cycles = interrupt(&sim, &mut mono, u64::MAX).unwrap();
+        assert!(cycles <= 1 << 30);
+        elapsed += cycles;
+        mono.set_compare(instant);
+    }
+    assert!(elapsed >= 10_000_000_000);
+    assert!(mono.health().is_ok());
+}
+
+#[test]
+fn idle_monotonic_interrupts_without_compare() {
+    let sim = Simulator::new();
+    let mut mono = Mono64::from_parts(sim.dwt(), sim.systick(), HZ);
+
+    // No compare is ever set: the overflows are still tracked by the
+    // interrupts re-arming the longest compare interval.
+    while sim.cycles() < 3 << 32 {
+        assert!(interrupt(&sim, &mut mono, max_interval(32)).is_some());
+    }
+    assert_eq!(mono.now().ticks(), sim.cycles());
+    assert!(mono.health().is_ok());
+}
+
+#[test]
+fn missed_wrap_is_detected() {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    static HOOKED: AtomicU32 = AtomicU32::new(0);
+    fn hook(anomaly: Anomaly) {
+        assert!(matches!(anomaly, Anomaly::MissedOverflow { .. }));
+        HOOKED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    let sim = Simulator::new();
+    let mut mono = Mono64::from_parts(sim.dwt(), sim.systick(), HZ).with_health_hook(hook);
+
+    // The interrupt is held off for a whole cycle counter period: the
+    // extended count did not advance although SysTick wrapped.
+    sim.advance(1 << 32);
+    assert!(interrupt(&sim, &mut mono, 0).is_some());
+    let health = mono.health();
+    assert_eq!(health.missed, 1);
+    assert_eq!(HOOKED.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn late_observation_is_detected() {
+    let sim = Simulator::new();
+    let mut mono = Mono64::from_parts(sim.dwt(), sim.systick(), HZ);
+    sim.advance(3 << 30);
+    mono.now();
+    let health = mono.health();
+    assert_eq!(health.late, 1);
+    assert_eq!(health.max_gap, 3 << 30);
+}
+
+#[test]
+fn u32_instants_wrap_without_anomalies() {
+    let sim = Simulator::new();
+    let mut mono = Mono32::from_parts(sim.dwt(), sim.systick(), HZ);
+    sim.advance(3 << 30);
+    mono.now();
+    sim.advance(3 << 30);
+    mono.now();
+    assert!(mono.health().is_ok());
+}
+
+#[test]
+fn compare_around_reset() {
+    for epoch in [Epoch::Boot, Epoch::Reset] {
+        let sim = Simulator::new();
+        let mut mono = Mono64::from_parts(sim.dwt(), sim.systick(), HZ).with_epoch(epoch);
+
+        // Scheduled during `init` relative to `zero()`, armed after `reset()`.
+        let before = Mono64::zero() + 1000u64.micros();
+        sim.advance(500);
+        unsafe { mono.reset() };
+        let reset = sim.cycles();
+        mono.set_compare(before);
+        interrupt(&sim, &mut mono, u64::MAX).unwrap();
+        let target = match epoch {
+            Epoch::Boot => 1000,
+            Epoch::Reset => reset + 1000,
+        };
+        assert!((target..target + 2).contains(&sim.cycles()), "{epoch:?}");
+        assert_eq!(mono.adjusted_now().ticks(), sim.cycles() - reset);
+
+        // Scheduled after `reset()`.
+        let after = mono.now() + 2000u64.micros();
+        let start = sim.cycles();
+        mono.set_compare(after);
+        interrupt(&sim, &mut mono, u64::MAX).unwrap();
+        assert!((2000..2002).contains(&(sim.cycles() - start)), "{epoch:?}");
+        assert!(mono.now() >= after);
+        assert_eq!(mono.deadline_misses().count, 0);
+    }
+}
+
+#[test]
+fn u32_instants_behind_reset() {
+    let sim = Simulator::new();
+    let mut mono = Mono32::from_parts(sim.dwt(), sim.systick(), HZ).with_epoch(Epoch::Reset);
+    sim.set_cyccnt(0xf000_0000);
+    unsafe { mono.reset() };
+
+    // Not observed for more than half the counter period: the extended
+    // count falls behind the `reset()` offset, the `u32` instants wrap.
+    sim.advance(0xa000_0000);
+    assert_eq!(mono.adjusted_now().ticks(), 0xa000_0000);
+    assert_eq!(mono.now().ticks(), 0xa000_0000);
+    // The held-off interrupt runs.
+    assert!(interrupt(&sim, &mut mono, 0).is_some());
+
+    let instant = mono.now() + 1000u32.micros();
+    mono.set_compare(instant);
+    interrupt(&sim, &mut mono, u64::MAX).unwrap();
+    assert!(mono.now() >= instant);
+}
+
+#[test]
+fn release_and_resume() {
+    let sim = Simulator::new();
+    let mut mono = Mono64::from_parts(sim.dwt(), sim.systick(), HZ);
+    let instant = mono.now() + 10u64.micros();
+    mono.set_compare(instant);
+    sim.advance(100);
+    assert!(sim.pending());
+
+    let (dwt, systick, suspended) = mono.release(true);
+    assert!(!sim.pending());
+    assert!(!sim.syst_interrupt_enabled());
+    assert_eq!(sim.advance_to_exception(1 << 28), None);
+
+    // The handler runs within the longest compare interval to set the
+    // next compare event.
+    let mut mono = Mono64::resume(dwt, systick, HZ, suspended);
+    assert!(sim.syst_interrupt_enabled());
+    assert!(interrupt(&sim, &mut mono, max_interval(32)).is_some());
+    assert_eq!(mono.now().ticks(), sim.cycles());
+    assert!(mono.health().is_ok());
+}
+
+#[test]
+fn observed_tracking_interrupts_once() {
+    let sim = Simulator::new();
+    let mut mono = Builder::<HZ>::new(HZ)
+        .overflow_tracking(OverflowTracking::Observed)
+        .build_from_parts::<_, _, u64>(sim.dwt(), sim.systick())
+        .unwrap();
+    let instant = mono.now() + 1000u64.micros();
+    mono.set_compare(instant);
+
+    assert!(interrupt(&sim, &mut mono, u64::MAX).is_some());
+    assert!(mono.now() >= instant);
+    assert_eq!(sim.advance_to_exception(1 << 28), None);
+    assert_eq!(sim.syst_wraps(), 1);
+}
+
+/// Pseudo-random delays up to `max` cycles.
+struct Delays(u64);
+
+impl Delays {
+    fn next(&mut self, max: u64) -> u64 {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (self.0 >> 33) % (max + 1)
+    }
+}
+
+#[test]
+fn overflow_extension_survives_preemption() {
+    for bits in [3, 8, 16, 32] {
+        let sim = Simulator::new();
+        sim.dwt().enable_cycle_counter();
+        let mask = u32::MAX >> (32 - bits);
+        let count = || sim.cyccnt() & mask;
+        let quarter = max_interval(bits);
+        let overflow = Overflow::new();
+        let mut delays = Delays(bits as u64);
+
+        // Several counter periods, the writer observing the counter at
+        // least every quarter period.
+        while sim.cycles() >> bits < 8 {
+            sim.advance(delays.next(quarter));
+
+            // The writer loaded the period and was preempted before its
+            // store, e.g. right after the counter crossed a half period: the
+            // reader still sees the previous period.
+            let extended = overflow.read(
+                || {
+                    sim.advance(delays.next(quarter));
+                    count()
+                },
+                bits,
+            );
+            assert_eq!(extended, sim.cycles(), "stale period, {bits} bits");
+            overflow.update(count(), bits);
+
+            // The reader was preempted between loading the period and
+            // reading the counter, and the writer stored a new period
+            // meanwhile.
+            let extended = overflow.read(
+                || {
+                    sim.advance(delays.next(quarter));
+                    overflow.update(count(), bits);
+                    sim.advance(delays.next(quarter));
+                    count()
+                },
+                bits,
+            );
+            assert_eq!(extended, sim.cycles(), "preempted read, {bits} bits");
+            assert_eq!(overflow.update(count(), bits), sim.cycles());
+        }
+    }
+}
+
+#[test]
+fn timer_divisor_spans_a_tick() {
+    let sim = Simulator::new();
+    let mono = DwtSystick::<HZ, _, _, u64>::from_parts(Narrow::<5>(sim.dwt()), sim.systick(), HZ);
+    // The longest compare interval of a 5 bit counter is 8 cycles, one
+    // tick short of the SysTick reload.
+    assert!(matches!(
+        mono.with_timer_divisor(5),
+        Err(Error::InvalidDivisor(5))
+    ));
+
+    let sim = Simulator::new();
+    sim.set_external_divisor(4);
+    let mut mono =
+        DwtSystick::<HZ, _, _, u64>::from_parts(Narrow::<5>(sim.dwt()), sim.systick(), HZ)
+            .with_systick_clock(SystickClock::External { divisor: 4 })
+            .unwrap();
+    let instant = mono.now() + 100u64.micros();
+    mono.set_compare(instant);
+    while mono.now() < instant {
+        assert!(interrupt(&sim, &mut mono, 8).unwrap() > 0);
+        mono.set_compare(instant);
+    }
+    assert!(mono.health().is_ok());
+}
+
+#[test]
+fn builder_rejects_configuration_beyond_counter_width() {
+    // Longer than the longest compare interval of 7 SysTick clocks.
+    let builder = Builder::<HZ>::new(HZ).min_reload(8);
+    let sim = Simulator::new();
+    let rejected = builder
+        .build_from_parts::<_, _, u64>(Narrow::<5>(sim.dwt()), sim.systick())
+        .err()
+        .unwrap();
+    assert_eq!(rejected.error, Error::InvalidReload(8));
+    assert!(!sim.syst_enabled());
+
+    let builder = Builder::<HZ>::new(HZ).systick_clock(SystickClock::External { divisor: 8 });
+    let sim = Simulator::new();
+    let rejected = builder
+        .build_from_parts::<_, _, u64>(Narrow::<5>(sim.dwt()), sim.systick())
+        .err()
+        .unwrap();
+    assert_eq!(rejected.error, Error::InvalidDivisor(8));
+    assert!(!sim.syst_enabled());
+
+    let sim = Simulator::new();
+    assert!(Builder::<HZ>::new(HZ)
+        .min_reload(7)
+        .build_from_parts::<_, _, u64>(Narrow::<5>(sim.dwt()), sim.systick())
+        .is_ok());
+}
+
+#[test]
+fn coarse_systick_is_not_late() {
+    let sim = Simulator::new();
+    sim.set_external_divisor(64);
+    let mut mono = Mono64::from_parts(sim.dwt(), sim.systick(), HZ)
+        .with_systick_clock(SystickClock::External { divisor: 64 })
+        .unwrap();
+
+    // The longest interval plus the SysTick clock phase and the interrupt
+    // latency exceed a quarter of the counter period.
+    while sim.cycles() < 3 << 32 {
+        sim.advance_to_exception(u64::MAX).unwrap();
+        sim.advance(1000);
+        assert!(sim.take_pending());
+        mono.clear_compare_flag();
+        mono.on_interrupt();
+    }
+    let health = mono.health();
+    assert!(health.max_gap > max_interval(32));
+    assert!(health.is_ok());
+}
+
+#[test]
+fn origin_is_published_whole() {
+    let origin = Origin::new();
+    assert_eq!(origin.read(), 0);
+    for cycles in [0x1_0000_0000, 0xffff_ffff, 0x1234_5678_9abc_def0, 0] {
+        origin.set(cycles);
+        assert_eq!(origin.read(), cycles);
+    }
+}
+
+#[test]
+fn spin_margin_releases_on_target() {
+    let sim = Simulator::new();
+    let mut mono = DwtSystick::<HZ, _, _, u64>::from_parts(Ticking(&sim), sim.systick(), HZ)
+        .with_spin_margin(100);
+    for _ in 0..4 {
+        let instant = mono.now() + 1000u64.micros();
+        mono.set_compare(instant);
+        sim.advance_to_exception(u64::MAX).unwrap();
+        assert!(sim.take_pending());
+        // Fired early by the margin and spun to the instant.
+        assert!(sim.cycles() < instant.ticks());
+        mono.clear_compare_flag();
+        assert_eq!(mono.wakeup_error(), Some(0));
+        assert_eq!(sim.cycles(), instant.ticks());
+        mono.on_interrupt();
+    }
+}
+
+#[test]
+fn spin_is_bounded() {
+    let sim = Simulator::new();
+    let mut mono = Mono64::from_parts(sim.dwt(), sim.systick(), HZ)
+        .with_lead_time(20)
+        .with_spin_margin(100);
+    let instant = mono.now() + 1000u64.micros();
+    mono.set_compare(instant);
+
+    // The counter does not advance while spinning: the spin gives up after
+    // the lead time plus margin in reads and reports the early release.
+    interrupt(&sim, &mut mono, u64::MAX).unwrap();
+    let error = mono.wakeup_error().unwrap();
+    assert!((-121..=-119).contains(&error), "{error}");
+}
+
+#[test]
+fn lead_time_calibration_converges() {
+    const LATENCY: u64 = 37;
+
+    let sim = Simulator::new();
+    let mut mono = Mono64::from_parts(sim.dwt(), sim.systick(), HZ);
+    assert_eq!(mono.wakeup_error(), None);
+    for _ in 0..4 {
+        let instant = mono.now() + 1000u64.micros();
+        mono.set_compare(instant);
+        // Exception entry and the handler prologue.
+        sim.advance_to_exception(u64::MAX).unwrap();
+        sim.advance(LATENCY);
+        assert!(sim.take_pending());
+        mono.clear_compare_flag();
+        mono.on_interrupt();
+        mono.calibrate_lead_time();
+    }
+    assert!((-1..=1).contains(&mono.wakeup_error().unwrap()));
+    assert!((LATENCY as u32 - 1..=LATENCY as u32 + 1).contains(&mono.lead_time()));
+}
+
+type Runtime<'a> = RuntimeDwtSystick<HZ, SimDwt<'a>, SimSyst<'a>, u64>;
+
+#[test]
+fn sysclk_change_keeps_time_continuous() {
+    let sim = Simulator::new();
+    let mut mono = Runtime::from_parts(sim.dwt(), sim.systick(), HZ);
+    sim.advance(1000);
+    assert_eq!(mono.now().ticks(), 1000);
+
+    // Cycles counted at the old frequency stay at their duration.
+    mono.set_sysclk(4 * HZ).unwrap();
+    assert_eq!(mono.sysclk(), 4 * HZ);
+    assert_eq!(mono.now().ticks(), 1000);
+    sim.advance(4002);
+    assert_eq!(mono.now().ticks(), 2000);
+
+    // The half microsecond left over is less than a cycle at the new
+    // frequency.
+    mono.set_sysclk(HZ / 2).unwrap();
+    assert_eq!(mono.now().ticks(), 2000);
+    sim.advance(500);
+    assert_eq!(mono.now().ticks(), 3000);
+    assert!(mono.set_sysclk(0).is_err());
+    assert_eq!(mono.sysclk(), HZ / 2);
+}
+
+#[test]
+fn sysclk_change_reprograms_compare() {
+    let sim = Simulator::new();
+    let mut mono = Runtime::from_parts(sim.dwt(), sim.systick(), HZ);
+    let instant = mono.now() + 1000u64.micros();
+    mono.set_compare(instant);
+    sim.advance(400);
+
+    // The remaining 600 us take four times the cycles.
+    mono.set_sysclk(4 * HZ).unwrap();
+    let elapsed = interrupt(&sim, &mut mono, u64::MAX).unwrap();
+    assert!((2400..2408).contains(&elapsed), "{elapsed}");
+    assert!(mono.now() >= instant);
+    assert_eq!(mono.now().ticks(), 1000);
+}
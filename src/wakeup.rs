@@ -0,0 +1,83 @@
+//! Wakeup latency compensation and precise wakeup
+
+/// Compare event lead time and the achieved wakeup error.
+pub(crate) struct Wakeup {
+    /// Cycles the compare event is programmed early.
+    lead: u32,
+    /// Additional cycles the compare event fires early to spin to the target.
+    margin: u32,
+    /// Extended cycle count of the pending compare event, if in SysTick range.
+    target: Option<u64>,
+    /// Cycles from the target to the last compare event.
+    error: Option<i64>,
+}
+
+impl Wakeup {
+    pub(crate) const fn new() -> Self {
+        Self {
+            lead: 0,
+            margin: 0,
+            target: None,
+            error: None,
+        }
+    }
+
+    pub(crate) fn lead(&self) -> u32 {
+        self.lead
+    }
+
+    pub(crate) fn set_lead(&mut self, lead: u32) {
+        self.lead = lead;
+    }
+
+    pub(crate) fn margin(&self) -> u32 {
+        self.margin
+    }
+
+    pub(crate) fn set_margin(&mut self, margin: u32) {
+        self.margin = margin;
+    }
+
+    pub(crate) fn error(&self) -> Option<i64> {
+        self.error
+    }
+
+    /// Add the last error to the lead time.
+    pub(crate) fn calibrate(&mut self) {
+        if let Some(error) = self.error {
+            self.lead = (self.lead as i64 + error).clamp(0, u32::MAX as i64) as u32;
+        }
+    }
+
+    /// Arm for a compare event `ticks` cycles after `now`, return the
+    /// compensated number of cycles to program.
+    ///
+    /// Events further than `range` cycles are reached by re-arming and not
+    /// measured.
+    pub(crate) fn arm(&mut self, now: u64, ticks: u64, range: u64) -> u64 {
+        let early = ticks.saturating_sub(self.lead as u64 + self.margin as u64);
+        self.target = (early <= range).then_some(now + ticks);
+        early
+    }
+
+    /// The target to spin to if the compare event fired early on purpose.
+    pub(crate) fn spin_target(&self, wrapped: bool) -> Option<u64> {
+        self.target.filter(|_| wrapped && self.margin > 0)
+    }
+
+    /// Most cycle counter reads when spinning: the cycles the compare event
+    /// fired early.
+    pub(crate) fn spin_limit(&self) -> u64 {
+        self.lead as u64 + self.margin as u64
+    }
+
+    /// The compare interrupt was entered at `now`, `wrapped` if the SysTick
+    /// period did elapse.
+    pub(crate) fn fired(&mut self, wrapped: bool, now: u64) {
+        if let Some(target) = self.target.take() {
+            if wrapped {
+                self.error = Some(now.wrapping_sub(target) as i64);
+            }
+        }
+    }
+}